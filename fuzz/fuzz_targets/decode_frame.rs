@@ -0,0 +1,54 @@
+// Feeds arbitrary bytes through the frame codec and every message decoder.
+// Run with `cargo fuzz run decode_frame -- -malloc_limit_mb=16` so a count
+// that gets deku to allocate far more than the frame holds fails the run.
+#![no_main]
+
+use aimclient::auth::ChallengeResponse;
+use aimclient::chat::ChatMessage;
+use aimclient::codec::{decode_raw, MessageKind, RawFrame};
+use aimclient::delta::SnapshotMessage;
+use aimclient::heartbeat::{Ping, Pong};
+use aimclient::network::{Hello, HelloResponse, PlayerSignal, ResponseSignal, ServerResponse};
+use aimclient::quantize::CompactState;
+use aimclient::session::{
+    JoinResponse, LeaveResponse, ServerRequest, SessionList, UdpBindResponse,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut buf = data;
+    while let Ok(Some((frame, len))) = decode_raw(buf) {
+        buf = &buf[len..];
+        // Every payload is tried as every kind, not just the one in its
+        // header, so the fuzzer does not have to find each kind first.
+        for id in 0..=u8::MAX {
+            if let Some(kind) = MessageKind::from_u8(id) {
+                decode(&RawFrame {
+                    kind,
+                    payload: frame.payload.clone(),
+                });
+            }
+        }
+    }
+});
+
+fn decode(frame: &RawFrame) {
+    let _ = match frame.kind {
+        MessageKind::ServerRequest => frame.decode::<ServerRequest>().map(drop),
+        MessageKind::PlayerSignal => frame.decode::<PlayerSignal>().map(drop),
+        MessageKind::ServerResponse => frame.decode::<ServerResponse>().map(drop),
+        MessageKind::JoinResponse => frame.decode::<JoinResponse>().map(drop),
+        MessageKind::ResponseSignal => frame.decode::<ResponseSignal>().map(drop),
+        MessageKind::UdpBindResponse => frame.decode::<UdpBindResponse>().map(drop),
+        MessageKind::Hello => frame.decode::<Hello>().map(drop),
+        MessageKind::HelloResponse => frame.decode::<HelloResponse>().map(drop),
+        MessageKind::SnapshotMessage => frame.decode::<SnapshotMessage>().map(drop),
+        MessageKind::CompactState => frame.decode::<CompactState>().map(drop),
+        MessageKind::Ping => frame.decode::<Ping>().map(drop),
+        MessageKind::Pong => frame.decode::<Pong>().map(drop),
+        MessageKind::LeaveResponse => frame.decode::<LeaveResponse>().map(drop),
+        MessageKind::SessionList => frame.decode::<SessionList>().map(drop),
+        MessageKind::ChatMessage => frame.decode::<ChatMessage>().map(drop),
+        MessageKind::ChallengeResponse => frame.decode::<ChallengeResponse>().map(drop),
+    };
+}
@@ -0,0 +1,260 @@
+use std::io;
+
+use deku::ctx::Endian;
+use deku::prelude::*;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::codec::{read_reply, wire_len, write_frame, MAX_WIRE_BYTES, WIRE_ENDIAN};
+use crate::connection::Stream;
+use crate::error::ClientError;
+use crate::network::Reason;
+use crate::session::{JoinResponse, ServerRequest, SessionOptions, SessionTicket};
+
+pub const SALT_SIZE: usize = 16;
+pub const NONCE_SIZE: usize = 32;
+pub const DIGEST_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The server only ever learns `verifier(salt, password)`: it is sent once when
+// the session is created and the password itself never leaves the client.
+pub fn verifier(salt: &[u8; SALT_SIZE], password: &str) -> [u8; DIGEST_SIZE] {
+    mac(salt, &[password.as_bytes()])
+}
+
+// Binds the proof to the nonce and to this exact join, so a captured proof is
+// no good for any other attempt.
+pub fn proof(
+    verifier: &[u8; DIGEST_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    id: &[u8],
+    nickname: &[u8],
+) -> [u8; DIGEST_SIZE] {
+    mac(verifier, &[nonce, id, nickname])
+}
+
+// Constant time, for the server side of the exchange.
+pub fn check_proof(
+    verifier: &[u8; DIGEST_SIZE],
+    nonce: &[u8; NONCE_SIZE],
+    id: &[u8],
+    nickname: &[u8],
+    proof: &[u8; DIGEST_SIZE],
+) -> bool {
+    let mut mac = HmacSha256::new_from_slice(verifier).unwrap();
+    update(&mut mac, &[nonce, id, nickname]);
+    mac.verify_slice(proof).is_ok()
+}
+
+pub fn random_bytes<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Every part is length prefixed so moving bytes from one part to the next
+// changes the result.
+fn mac(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_SIZE] {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    update(&mut mac, parts);
+    mac.finalize().into_bytes().into()
+}
+
+fn update(mac: &mut HmacSha256, parts: &[&[u8]]) {
+    for part in parts {
+        mac.update(&(part.len() as u32).to_be_bytes());
+        mac.update(part);
+    }
+}
+
+// Creates a session without sending the password. An empty password still
+// gets a verifier, the server just lists the session as open.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct VerifiedSessionRequest {
+    #[deku(update = "self.id.len()", assert = "*id_count <= MAX_WIRE_BYTES")]
+    id_count: u16,
+    #[deku(count = "id_count")]
+    pub id: Vec<u8>,
+    #[deku(update = "self.nickname.len()", assert = "*nickname_count <= MAX_WIRE_BYTES")]
+    nickname_count: u16,
+    #[deku(count = "nickname_count")]
+    pub nickname: Vec<u8>,
+    pub options: SessionOptions,
+    pub has_password: bool,
+    pub salt: [u8; SALT_SIZE],
+    pub verifier: [u8; DIGEST_SIZE],
+}
+
+impl VerifiedSessionRequest {
+    pub fn new(
+        id: &str,
+        password: &str,
+        nickname: &str,
+        options: SessionOptions,
+    ) -> io::Result<Self> {
+        let salt = random_bytes()?;
+        Ok(Self {
+            id_count: wire_len(id.len()),
+            id: id.as_bytes().to_vec(),
+            nickname_count: wire_len(nickname.len()),
+            nickname: nickname.as_bytes().to_vec(),
+            options,
+            has_password: !password.is_empty(),
+            salt,
+            verifier: verifier(&salt, password),
+        })
+    }
+}
+
+// First half of a join: asks the server for a challenge.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct ChallengeRequest {
+    #[deku(update = "self.id.len()", assert = "*id_count <= MAX_WIRE_BYTES")]
+    id_count: u16,
+    #[deku(count = "id_count")]
+    pub id: Vec<u8>,
+    #[deku(update = "self.nickname.len()", assert = "*nickname_count <= MAX_WIRE_BYTES")]
+    nickname_count: u16,
+    #[deku(count = "nickname_count")]
+    pub nickname: Vec<u8>,
+}
+
+impl ChallengeRequest {
+    pub fn new(id: &str, nickname: &str) -> Self {
+        Self {
+            id_count: wire_len(id.len()),
+            id: id.as_bytes().to_vec(),
+            nickname_count: wire_len(nickname.len()),
+            nickname: nickname.as_bytes().to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct Challenge {
+    pub salt: [u8; SALT_SIZE],
+    pub nonce: [u8; NONCE_SIZE],
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
+pub enum ChallengeResponse {
+    #[deku(id = "0x1")]
+    Ok(Challenge),
+    #[deku(id = "0x2")]
+    Err(Reason),
+}
+
+// Second half of a join, answered with a `JoinResponse`. A proof that does
+// not check out is refused with `WrongPassword`.
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct ProofRequest {
+    pub proof: [u8; DIGEST_SIZE],
+}
+
+pub async fn join(
+    stream: &mut Stream,
+    id: &str,
+    password: &str,
+    nickname: &str,
+) -> Result<SessionTicket, ClientError> {
+    let request = ServerRequest::Challenge(ChallengeRequest::new(id, nickname));
+    write_frame(stream, &request).await?;
+    let challenge = match read_reply(stream).await? {
+        ChallengeResponse::Ok(challenge) => challenge,
+        ChallengeResponse::Err(reason) => return Err(reason.into()),
+    };
+    let verifier = verifier(&challenge.salt, password);
+    let proof = proof(&verifier, &challenge.nonce, id.as_bytes(), nickname.as_bytes());
+    write_frame(stream, &ServerRequest::Proof(ProofRequest { proof })).await?;
+    match read_reply(stream).await? {
+        JoinResponse::Ok(ticket) => Ok(ticket),
+        JoinResponse::Err(reason) => Err(reason.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::codec::testing::{frame_round_trip, reason, round_trip, text};
+    use crate::session::{GameMode, SessionOptions, Visibility};
+
+    fn login(password: &str, attempt: &str, nickname: &[u8]) -> bool {
+        let salt = random_bytes().unwrap();
+        let nonce = random_bytes().unwrap();
+        let proof = proof(&verifier(&salt, attempt), &nonce, b"room", b"ana");
+        check_proof(&verifier(&salt, password), &nonce, b"room", nickname, &proof)
+    }
+
+    #[test]
+    fn right_password_logs_in() {
+        assert!(login("hunter2", "hunter2", b"ana"));
+        assert!(login("", "", b"ana"));
+    }
+
+    #[test]
+    fn wrong_password_is_refused() {
+        assert!(!login("hunter2", "hunter3", b"ana"));
+        assert!(!login("hunter2", "", b"ana"));
+    }
+
+    #[test]
+    fn proof_is_bound_to_the_join() {
+        assert!(!login("hunter2", "hunter2", b"bob"));
+    }
+
+    #[test]
+    fn proof_is_bound_to_the_nonce() {
+        let salt = random_bytes().unwrap();
+        let verifier = verifier(&salt, "hunter2");
+        let proof = proof(&verifier, &[1; NONCE_SIZE], b"room", b"ana");
+        assert!(!check_proof(&verifier, &[2; NONCE_SIZE], b"room", b"ana", &proof));
+    }
+
+    #[test]
+    fn parts_cannot_be_shifted_between_fields() {
+        let verifier = verifier(&[0; SALT_SIZE], "hunter2");
+        let nonce = [0; NONCE_SIZE];
+        let proof = |id: &[u8], nickname: &[u8]| proof(&verifier, &nonce, id, nickname);
+        assert_ne!(proof(b"room", b"ana"), proof(b"roo", b"mana"));
+    }
+
+    fn challenge_response() -> impl Strategy<Value = ChallengeResponse> {
+        prop_oneof![
+            any::<([u8; SALT_SIZE], [u8; NONCE_SIZE])>()
+                .prop_map(|(salt, nonce)| ChallengeResponse::Ok(Challenge { salt, nonce })),
+            reason().prop_map(ChallengeResponse::Err),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn challenge_request_round_trips(id in text(), nickname in text()) {
+            round_trip(&ChallengeRequest::new(&id, &nickname))?;
+        }
+
+        #[test]
+        fn challenge_response_round_trips(response in challenge_response()) {
+            frame_round_trip(&response)?;
+        }
+    }
+
+    #[test]
+    fn verified_session_request_round_trips() {
+        let options = SessionOptions::new(8, "arena", GameMode::Teams, Visibility::Private, 600);
+        let request = VerifiedSessionRequest::new("room", "secret", "ana", options).unwrap();
+        frame_round_trip(&ServerRequest::NewVerifiedSession(request)).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+use crate::session::SessionInfo;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Name,
+    Players,
+}
+
+// The last session list fetched from the server. Filtering and sorting happen
+// locally so typing in the filter box doesn't hit the server.
+pub struct SessionBrowser {
+    sessions: Vec<SessionInfo>,
+    pub sort: SortKey,
+    pub hide_full: bool,
+}
+
+impl Default for SessionBrowser {
+    fn default() -> Self {
+        Self {
+            sessions: Vec::new(),
+            sort: SortKey::Name,
+            hide_full: false,
+        }
+    }
+}
+
+impl SessionBrowser {
+    // Ids end up on buttons, which cannot show a NUL, so sessions whose id
+    // the player could not type in anyway are left out.
+    pub fn set_sessions(&mut self, sessions: Vec<SessionInfo>) {
+        self.sessions = sessions
+            .into_iter()
+            .filter(SessionInfo::has_printable_id)
+            .collect();
+    }
+
+    pub fn toggle_sort(&mut self) {
+        self.sort = match self.sort {
+            SortKey::Name => SortKey::Players,
+            SortKey::Players => SortKey::Name,
+        };
+    }
+
+    pub fn visible(&self, filter: &str) -> Vec<&SessionInfo> {
+        let filter = filter.to_lowercase();
+        let mut sessions = self
+            .sessions
+            .iter()
+            .filter(|session| !(self.hide_full && session.is_full()))
+            .filter(|session| session.name().to_lowercase().contains(&filter))
+            .collect::<Vec<_>>();
+        match self.sort {
+            SortKey::Name => sessions.sort_by_key(|session| session.name().to_lowercase()),
+            SortKey::Players => sessions.sort_by(|a, b| {
+                b.player_count
+                    .cmp(&a.player_count)
+                    .then_with(|| a.name().cmp(&b.name()))
+            }),
+        }
+        sessions
+    }
+}
+
+pub fn describe(session: &SessionInfo) -> String {
+    let lock = if session.has_password { " (password)" } else { "" };
+    format!(
+        "{} {}/{}{lock}",
+        session.name(),
+        session.player_count,
+        session.player_limit
+    )
+}
@@ -0,0 +1,187 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use deku::ctx::Endian;
+use deku::prelude::*;
+
+use crate::codec::{wire_len, MAX_WIRE_BYTES, WIRE_ENDIAN};
+use crate::error::ClientError;
+use crate::session::validate_nickname;
+
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+const MAX_HISTORY: usize = 100;
+const VISIBLE_LINES: usize = 8;
+const FADE_AFTER: Duration = Duration::from_secs(8);
+const FADE_DURATION: Duration = Duration::from_secs(2);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
+pub enum ChatChannel {
+    #[deku(id = "0x1")]
+    All,
+    #[deku(id = "0x2")]
+    Team,
+}
+
+impl ChatChannel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChatChannel::All => "All",
+            ChatChannel::Team => "Team",
+        }
+    }
+}
+
+// Sent by the client with an empty sender; the server fills it in before
+// relaying the message to everyone on the channel.
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct ChatMessage {
+    pub channel: ChatChannel,
+    #[deku(update = "self.sender.len()", assert = "*sender_count <= MAX_WIRE_BYTES")]
+    sender_count: u16,
+    #[deku(count = "sender_count")]
+    pub sender: Vec<u8>,
+    #[deku(update = "self.text.len()", assert = "*text_count <= MAX_WIRE_BYTES")]
+    text_count: u16,
+    #[deku(count = "text_count")]
+    pub text: Vec<u8>,
+}
+
+impl ChatMessage {
+    pub fn new(channel: ChatChannel, text: &str) -> Self {
+        Self {
+            channel,
+            sender_count: 0,
+            sender: Vec::new(),
+            text_count: wire_len(text.len()),
+            text: text.as_bytes().to_vec(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ChatLine {
+    pub channel: ChatChannel,
+    pub sender: String,
+    pub text: String,
+}
+
+impl TryFrom<ChatMessage> for ChatLine {
+    type Error = ClientError;
+
+    // Relayed text goes straight to the screen, which cannot draw a NUL, so
+    // it gets the same checks as what the player can type.
+    fn try_from(message: ChatMessage) -> Result<Self, Self::Error> {
+        let text = String::from_utf8(message.text)?;
+        let length = text.chars().count();
+        if length > MAX_CHAT_LENGTH {
+            return Err(ClientError::TooLong {
+                length,
+                limit: MAX_CHAT_LENGTH,
+            });
+        }
+        if text.chars().any(char::is_control) {
+            return Err(ClientError::Unprintable);
+        }
+        let sender = String::from_utf8(message.sender)?;
+        validate_nickname(&sender)?;
+        Ok(Self {
+            channel: message.channel,
+            sender,
+            text,
+        })
+    }
+}
+
+// Received lines fade out after a while unless the chat is open, in which
+// case the whole history can be scrolled through.
+#[derive(Default)]
+pub struct ChatHistory {
+    lines: VecDeque<(ChatLine, Instant)>,
+    scroll: usize,
+}
+
+impl ChatHistory {
+    pub fn push(&mut self, line: ChatLine, now: Instant) {
+        self.lines.push_back((line, now));
+        if self.lines.len() > MAX_HISTORY {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn scroll(&mut self, lines: i32) {
+        let max = self.lines.len().saturating_sub(VISIBLE_LINES);
+        self.scroll = (self.scroll as i64 + lines as i64).clamp(0, max as i64) as usize;
+    }
+
+    pub fn reset_scroll(&mut self) {
+        self.scroll = 0;
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.scroll = 0;
+    }
+
+    // Newest last, each with the opacity it should be drawn with.
+    pub fn visible(&self, now: Instant, open: bool) -> Vec<(&ChatLine, f32)> {
+        let end = self.lines.len() - self.scroll.min(self.lines.len());
+        let start = end.saturating_sub(VISIBLE_LINES);
+        self.lines
+            .range(start..end)
+            .filter_map(|(line, received)| {
+                if open {
+                    return Some((line, 1.0));
+                }
+                let age = now.saturating_duration_since(*received);
+                let fade =
+                    age.saturating_sub(FADE_AFTER).as_secs_f32() / FADE_DURATION.as_secs_f32();
+                (fade < 1.0).then_some((line, 1.0 - fade))
+            })
+            .collect()
+    }
+}
+
+// The line being typed. Input is limited to `MAX_CHAT_LENGTH` characters as
+// it is typed, and control characters are dropped.
+pub struct ChatInput {
+    pub channel: ChatChannel,
+    pub text: String,
+}
+
+impl Default for ChatInput {
+    fn default() -> Self {
+        Self {
+            channel: ChatChannel::All,
+            text: String::new(),
+        }
+    }
+}
+
+impl ChatInput {
+    pub fn push(&mut self, c: char) {
+        if !c.is_control() && self.text.chars().count() < MAX_CHAT_LENGTH {
+            self.text.push(c);
+        }
+    }
+
+    pub fn toggle_channel(&mut self) {
+        self.channel = match self.channel {
+            ChatChannel::All => ChatChannel::Team,
+            ChatChannel::Team => ChatChannel::All,
+        };
+    }
+
+    pub fn take(&mut self) -> Option<ChatMessage> {
+        let text = std::mem::take(&mut self.text);
+        let text = text.trim();
+        (!text.is_empty()).then(|| ChatMessage::new(self.channel, text))
+    }
+}
@@ -0,0 +1,557 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use deku::ctx::Endian;
+use deku::prelude::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+use crate::auth::ChallengeResponse;
+use crate::chat::ChatMessage;
+use crate::delta::SnapshotMessage;
+use crate::heartbeat::{Ping, Pong};
+use crate::network::{Hello, HelloResponse, PlayerSignal, ResponseSignal, ServerResponse};
+use crate::quantize::CompactState;
+use crate::session::{JoinResponse, LeaveResponse, ServerRequest, SessionList, UdpBindResponse};
+
+// Every frame starts with a big-endian u32 payload length followed by a
+// one byte message kind, so the reader always knows how much to wait for.
+pub const HEADER_SIZE: usize = 5;
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+// How long a request waits for its answer before the server is given up on.
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+// Upper bounds on lengths read off the wire, checked before the data they
+// describe is read. deku sizes vectors up front from the count it is given.
+pub const MAX_WIRE_ITEMS: u16 = 1024;
+pub const MAX_WIRE_BYTES: u16 = 1024;
+// Every multi-byte field goes over the wire big endian, whatever the machine.
+// Wire types take the byte order as context so nested types inherit it.
+pub const WIRE_ENDIAN: Endian = Endian::Big;
+
+// Lengths are sent as u16. Anything longer saturates, which the bound checks
+// then refuse to encode instead of sending a count that does not match.
+pub fn wire_len(len: usize) -> u16 {
+    u16::try_from(len).unwrap_or(u16::MAX)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    ServerRequest = 0x1,
+    PlayerSignal = 0x2,
+    ServerResponse = 0x3,
+    JoinResponse = 0x4,
+    ResponseSignal = 0x5,
+    UdpBindResponse = 0x6,
+    Hello = 0x7,
+    HelloResponse = 0x8,
+    SnapshotMessage = 0x9,
+    CompactState = 0xa,
+    Ping = 0xb,
+    Pong = 0xc,
+    LeaveResponse = 0xd,
+    SessionList = 0xe,
+    ChatMessage = 0xf,
+    ChallengeResponse = 0x10,
+}
+
+impl MessageKind {
+    pub fn from_u8(id: u8) -> Option<Self> {
+        use MessageKind::*;
+        match id {
+            0x1 => Some(ServerRequest),
+            0x2 => Some(PlayerSignal),
+            0x3 => Some(ServerResponse),
+            0x4 => Some(JoinResponse),
+            0x5 => Some(ResponseSignal),
+            0x6 => Some(UdpBindResponse),
+            0x7 => Some(Hello),
+            0x8 => Some(HelloResponse),
+            0x9 => Some(SnapshotMessage),
+            0xa => Some(CompactState),
+            0xb => Some(Ping),
+            0xc => Some(Pong),
+            0xd => Some(LeaveResponse),
+            0xe => Some(SessionList),
+            0xf => Some(ChatMessage),
+            0x10 => Some(ChallengeResponse),
+            _ => None,
+        }
+    }
+}
+
+pub trait Message: DekuContainerWrite + for<'a> DekuContainerRead<'a> {
+    const KIND: MessageKind;
+}
+
+impl Message for ServerRequest {
+    const KIND: MessageKind = MessageKind::ServerRequest;
+}
+
+impl Message for PlayerSignal {
+    const KIND: MessageKind = MessageKind::PlayerSignal;
+}
+
+impl Message for ServerResponse {
+    const KIND: MessageKind = MessageKind::ServerResponse;
+}
+
+impl Message for JoinResponse {
+    const KIND: MessageKind = MessageKind::JoinResponse;
+}
+
+impl Message for ResponseSignal {
+    const KIND: MessageKind = MessageKind::ResponseSignal;
+}
+
+impl Message for UdpBindResponse {
+    const KIND: MessageKind = MessageKind::UdpBindResponse;
+}
+
+impl Message for Hello {
+    const KIND: MessageKind = MessageKind::Hello;
+}
+
+impl Message for HelloResponse {
+    const KIND: MessageKind = MessageKind::HelloResponse;
+}
+
+impl Message for SnapshotMessage {
+    const KIND: MessageKind = MessageKind::SnapshotMessage;
+}
+
+impl Message for CompactState {
+    const KIND: MessageKind = MessageKind::CompactState;
+}
+
+impl Message for Ping {
+    const KIND: MessageKind = MessageKind::Ping;
+}
+
+impl Message for Pong {
+    const KIND: MessageKind = MessageKind::Pong;
+}
+
+impl Message for LeaveResponse {
+    const KIND: MessageKind = MessageKind::LeaveResponse;
+}
+
+impl Message for SessionList {
+    const KIND: MessageKind = MessageKind::SessionList;
+}
+
+impl Message for ChatMessage {
+    const KIND: MessageKind = MessageKind::ChatMessage;
+}
+
+impl Message for ChallengeResponse {
+    const KIND: MessageKind = MessageKind::ChallengeResponse;
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(io::Error),
+    TooLarge(usize),
+    UnknownKind(u8),
+    UnexpectedKind {
+        expected: MessageKind,
+        found: MessageKind,
+    },
+    Encode(DekuError),
+    Decode(DekuError),
+    TrailingBytes(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use FrameError::*;
+        match self {
+            Io(err) => write!(f, "connection error: {err}"),
+            TooLarge(size) => write!(
+                f,
+                "frame of {size} bytes exceeds the {MAX_FRAME_SIZE} byte limit"
+            ),
+            UnknownKind(id) => write!(f, "unknown message kind {id:#x}"),
+            UnexpectedKind { expected, found } => {
+                write!(f, "expected a {expected:?} frame but got {found:?}")
+            }
+            Encode(err) => write!(f, "could not encode message: {err}"),
+            Decode(err) => write!(f, "could not decode message: {err}"),
+            TrailingBytes(count) => write!(f, "{count} unread bytes left in frame"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<io::Error> for FrameError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+#[derive(Clone)]
+pub struct RawFrame {
+    pub kind: MessageKind,
+    pub payload: Vec<u8>,
+}
+
+impl RawFrame {
+    pub fn decode<M: Message>(&self) -> Result<M, FrameError> {
+        if self.kind != M::KIND {
+            return Err(FrameError::UnexpectedKind {
+                expected: M::KIND,
+                found: self.kind,
+            });
+        }
+        let ((rest, _), message) =
+            M::from_bytes((self.payload.as_slice(), 0)).map_err(FrameError::Decode)?;
+        if !rest.is_empty() {
+            return Err(FrameError::TrailingBytes(rest.len()));
+        }
+        Ok(message)
+    }
+}
+
+pub fn encode<M: Message>(message: &M) -> Result<Vec<u8>, FrameError> {
+    let payload = message.to_bytes().map_err(FrameError::Encode)?;
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(payload.len()));
+    }
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.push(M::KIND as u8);
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+// Returns `Ok(None)` while `buf` does not hold a whole frame yet, otherwise
+// the frame and the number of bytes it took up.
+pub fn decode_raw(buf: &[u8]) -> Result<Option<(RawFrame, usize)>, FrameError> {
+    if buf.len() < HEADER_SIZE {
+        return Ok(None);
+    }
+    let (kind, len) = parse_header(&buf[..HEADER_SIZE])?;
+    if buf.len() < HEADER_SIZE + len {
+        return Ok(None);
+    }
+    let payload = buf[HEADER_SIZE..HEADER_SIZE + len].to_vec();
+    Ok(Some((RawFrame { kind, payload }, HEADER_SIZE + len)))
+}
+
+pub async fn write_frame<M: Message, W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &M,
+) -> Result<(), FrameError> {
+    writer.write_all(&encode(message)?).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+pub async fn read_raw_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<RawFrame, FrameError> {
+    let mut header = [0; HEADER_SIZE];
+    reader.read_exact(&mut header).await?;
+    let (kind, len) = parse_header(&header)?;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(RawFrame { kind, payload })
+}
+
+pub async fn read_frame<M: Message, R: AsyncRead + Unpin>(reader: &mut R) -> Result<M, FrameError> {
+    read_raw_frame(reader).await?.decode()
+}
+
+// Keeps whatever part of a frame has arrived in its own buffer, so unlike
+// `read_raw_frame` a `next` that gets cancelled (e.g. by losing a `select!`)
+// does not leave the stream in the middle of a frame.
+pub struct FrameReader<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buf: Vec::new(),
+        }
+    }
+
+    pub async fn next(&mut self) -> Result<RawFrame, FrameError> {
+        loop {
+            if let Some((frame, used)) = decode_raw(&self.buf)? {
+                self.buf.drain(..used);
+                return Ok(frame);
+            }
+            let mut chunk = [0; 4096];
+            let read = self.reader.read(&mut chunk).await?;
+            if read == 0 {
+                return Err(FrameError::Io(io::ErrorKind::UnexpectedEof.into()));
+            }
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+// For request/response exchanges, which run on the render loop: a server
+// that accepts the connection but never answers must not freeze the window.
+pub async fn read_reply<M: Message, R: AsyncRead + Unpin>(reader: &mut R) -> Result<M, FrameError> {
+    match timeout(REPLY_TIMEOUT, read_frame(reader)).await {
+        Ok(result) => result,
+        Err(_) => Err(FrameError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the server did not reply in time",
+        ))),
+    }
+}
+
+fn parse_header(header: &[u8]) -> Result<(MessageKind, usize), FrameError> {
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(len));
+    }
+    let kind = MessageKind::from_u8(header[4]).ok_or(FrameError::UnknownKind(header[4]))?;
+    Ok((kind, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    fn header(len: usize, kind: u8) -> Vec<u8> {
+        let mut header = (len as u32).to_be_bytes().to_vec();
+        header.push(kind);
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_a_frame_split_across_writes() {
+        let frame = encode(&Ping { id: 0xdead_beef }).unwrap();
+        let (mut client, mut server) = duplex(64);
+        let writer = tokio::spawn(async move {
+            for chunk in frame.chunks(2) {
+                server.write_all(chunk).await.unwrap();
+                server.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let ping: Ping = read_frame(&mut client).await.unwrap();
+        assert_eq!(ping.id, 0xdead_beef);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_frames_merged_into_one_write() {
+        let mut bytes = encode(&Ping { id: 1 }).unwrap();
+        bytes.extend(encode(&Pong { id: 2 }).unwrap());
+        let (mut client, mut server) = duplex(64);
+        server.write_all(&bytes).await.unwrap();
+        let ping: Ping = read_frame(&mut client).await.unwrap();
+        let pong: Pong = read_frame(&mut client).await.unwrap();
+        assert_eq!((ping.id, pong.id), (1, 2));
+    }
+
+    #[test]
+    fn decode_raw_waits_for_a_whole_frame() {
+        let frame = encode(&Ping { id: 3 }).unwrap();
+        for len in 0..frame.len() {
+            assert!(decode_raw(&frame[..len]).unwrap().is_none());
+        }
+        let mut bytes = frame.clone();
+        bytes.extend_from_slice(&frame[..2]);
+        let (raw, used) = decode_raw(&bytes).unwrap().unwrap();
+        assert_eq!(used, frame.len());
+        assert_eq!(raw.decode::<Ping>().unwrap().id, 3);
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let oversized = header(MAX_FRAME_SIZE + 1, MessageKind::Ping as u8);
+        assert!(matches!(
+            parse_header(&oversized),
+            Err(FrameError::TooLarge(size)) if size == MAX_FRAME_SIZE + 1
+        ));
+        assert!(parse_header(&header(MAX_FRAME_SIZE, MessageKind::Ping as u8)).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_kinds() {
+        assert!(matches!(
+            parse_header(&header(0, 0xff)),
+            Err(FrameError::UnknownKind(0xff))
+        ));
+        assert!(matches!(
+            parse_header(&header(0, 0)),
+            Err(FrameError::UnknownKind(0))
+        ));
+    }
+
+    #[test]
+    fn rejects_unexpected_kinds() {
+        let frame = RawFrame {
+            kind: MessageKind::Pong,
+            payload: 4u32.to_be_bytes().to_vec(),
+        };
+        assert!(matches!(
+            frame.decode::<Ping>(),
+            Err(FrameError::UnexpectedKind {
+                expected: MessageKind::Ping,
+                found: MessageKind::Pong,
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let frame = RawFrame {
+            kind: MessageKind::Ping,
+            payload: vec![0, 0, 0, 4, 0xaa, 0xbb],
+        };
+        assert!(matches!(
+            frame.decode::<Ping>(),
+            Err(FrameError::TrailingBytes(2))
+        ));
+    }
+
+    #[tokio::test]
+    async fn frame_reader_survives_cancellation() {
+        let frame = encode(&Ping { id: 9 }).unwrap();
+        let (client, mut server) = duplex(64);
+        let mut reader = FrameReader::new(client);
+        server.write_all(&frame[..HEADER_SIZE + 1]).await.unwrap();
+        let cancelled = timeout(Duration::from_millis(20), reader.next()).await;
+        assert!(cancelled.is_err());
+        server.write_all(&frame[HEADER_SIZE + 1..]).await.unwrap();
+        server.write_all(&encode(&Pong { id: 10 }).unwrap()).await.unwrap();
+        assert_eq!(reader.next().await.unwrap().decode::<Ping>().unwrap().id, 9);
+        assert_eq!(reader.next().await.unwrap().decode::<Pong>().unwrap().id, 10);
+        drop(server);
+        assert!(matches!(reader.next().await, Err(FrameError::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn eof_inside_a_frame_is_an_io_error() {
+        let frame = encode(&Ping { id: 5 }).unwrap();
+        for len in [2, HEADER_SIZE, frame.len() - 1] {
+            let (mut client, mut server) = duplex(64);
+            server.write_all(&frame[..len]).await.unwrap();
+            drop(server);
+            let result = read_frame::<Ping, _>(&mut client).await;
+            assert!(matches!(
+                result,
+                Err(FrameError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof
+            ));
+        }
+    }
+}
+
+// Round-trip checks, and strategies for the wire types that the tests of more
+// than one module build.
+#[cfg(test)]
+pub(crate) mod testing {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::sample::select;
+
+    use super::*;
+    use crate::network::{Reason, RemotePlayer};
+    use crate::objects::NetworkObject;
+
+    // Through the whole codec: the frame comes back as the same bytes and
+    // takes up exactly what was encoded.
+    pub fn frame_round_trip<M: Message>(message: &M) -> Result<(), TestCaseError> {
+        let frame = encode(message).unwrap();
+        let (raw, len) = decode_raw(&frame).unwrap().unwrap();
+        prop_assert_eq!(len, frame.len());
+        prop_assert_eq!(encode(&raw.decode::<M>().unwrap()).unwrap(), frame);
+        Ok(())
+    }
+
+    // Compared as bytes, so NaNs in float fields still count as equal.
+    pub fn round_trip<T>(value: &T) -> Result<(), TestCaseError>
+    where
+        T: DekuContainerWrite + for<'a> DekuContainerRead<'a>,
+    {
+        let bytes = value.to_bytes().unwrap();
+        let ((rest, _), decoded) = T::from_bytes((bytes.as_slice(), 0)).unwrap();
+        prop_assert!(rest.is_empty());
+        prop_assert_eq!(decoded.to_bytes().unwrap(), bytes);
+        Ok(())
+    }
+
+    pub fn text() -> impl Strategy<Value = String> {
+        "\\PC{0,16}"
+    }
+
+    pub fn bytes() -> impl Strategy<Value = Vec<u8>> {
+        vec(any::<u8>(), 0..16)
+    }
+
+    pub fn reason() -> impl Strategy<Value = Reason> {
+        use Reason::*;
+        select(vec![
+            IdInUse,
+            InvalidRequestFormat,
+            InvalidIdFormat,
+            InvalidPassword,
+            IdDoesntExist,
+            WrongPassword,
+            VersionMismatch,
+            UnknownResumeToken,
+            InvalidPlayerLimit,
+            InvalidMap,
+            UnsupportedGameMode,
+            InvalidRoundTime,
+            InvalidNickname,
+            NicknameInUse,
+            SessionFull,
+        ])
+    }
+
+    prop_compose! {
+        pub fn remote_player()(
+            id in any::<u32>(),
+            nickname in bytes(),
+            position in any::<[f32; 3]>(),
+            rotation in any::<[f32; 4]>(),
+            team in any::<u8>(),
+        ) -> RemotePlayer {
+            RemotePlayer::new(id, nickname, position, rotation, team)
+        }
+    }
+
+    prop_compose! {
+        pub fn network_object()(
+            position in any::<[f32; 3]>(),
+            rotation in any::<[f32; 4]>(),
+            id in bytes(),
+        ) -> NetworkObject {
+            NetworkObject::new(position, rotation, id)
+        }
+    }
+
+    prop_compose! {
+        pub fn response_signal()(
+            last_input in any::<u32>(),
+            vectors in any::<[[f32; 3]; 5]>(),
+            players in vec(remote_player(), 0..4),
+            objects in vec(network_object(), 0..4),
+        ) -> ResponseSignal {
+            let [translation, camera_pos, camera_target, fwd, right] = vectors;
+            ResponseSignal {
+                player_count: wire_len(players.len()),
+                object_count: wire_len(objects.len()),
+                last_input,
+                translation,
+                camera_pos,
+                camera_target,
+                fwd,
+                right,
+                players,
+                objects,
+            }
+        }
+    }
+}
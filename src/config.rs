@@ -0,0 +1,352 @@
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+
+use crate::heartbeat::{DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_SILENCE_TIMEOUT};
+use crate::interpolation::DEFAULT_INTERPOLATION_DELAY;
+use crate::netsim::NetConditions;
+use crate::player::Movement;
+use crate::quantize::{Quantization, MAX_POSITION_BITS, MAX_ROTATION_BITS};
+use crate::session::validate_nickname;
+
+pub const CONFIG_PATH: &str = "client.cfg";
+pub const DEFAULT_HOST: &str = "127.0.0.1";
+pub const DEFAULT_PORT: u16 = 9001;
+pub const DEFAULT_NICKNAME: &str = "Player";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    Udp,
+}
+
+impl Transport {
+    fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "tcp" => Some(Transport::Tcp),
+            "udp" => Some(Transport::Udp),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    pub transport: Transport,
+    pub nickname: String,
+    pub quantization: Quantization,
+    pub movement: Movement,
+    pub heartbeat_interval: Duration,
+    pub silence_timeout: Duration,
+    // How far in the past remote players and objects are rendered.
+    pub interpolation_delay: Duration,
+    // Demo file to record snapshots to while in a session.
+    pub record: Option<String>,
+    // Demo file to play back instead of connecting.
+    pub play: Option<String>,
+    // Simulated network conditions, set with `net_<key>`.
+    pub net: NetConditions,
+    pub tls: bool,
+    pub tls_ca: Option<String>,
+    pub tls_fingerprint: Option<[u8; 32]>,
+    // Name to check the certificate against, the host by default.
+    pub tls_name: Option<String>,
+    // Never send a password in plain text, refusing servers that cannot
+    // check it through a challenge instead. Empty passwords are still sent.
+    // Off by default so older servers can still be joined.
+    pub require_challenge_auth: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: DEFAULT_HOST.into(),
+            port: DEFAULT_PORT,
+            transport: Transport::Tcp,
+            nickname: DEFAULT_NICKNAME.into(),
+            quantization: Quantization::default(),
+            movement: Movement::default(),
+            heartbeat_interval: DEFAULT_HEARTBEAT_INTERVAL,
+            silence_timeout: DEFAULT_SILENCE_TIMEOUT,
+            interpolation_delay: DEFAULT_INTERPOLATION_DELAY,
+            record: None,
+            play: None,
+            net: NetConditions::default(),
+            tls: false,
+            tls_ca: None,
+            tls_fingerprint: None,
+            tls_name: None,
+            require_challenge_auth: false,
+        }
+    }
+}
+
+impl Config {
+    // Settings come from `client.cfg` (one `key = value` per line, `#` starts
+    // a comment), then `AIMCLIENT_<KEY>` variables, then `--key value` or
+    // `--key=value` arguments, each overriding the one before.
+    pub fn load() -> Self {
+        let file = std::fs::read_to_string(CONFIG_PATH)
+            .map(|file| parse_file(&file))
+            .unwrap_or_default();
+        let env = std::env::vars()
+            .filter_map(|(key, value)| {
+                let key = key.strip_prefix("AIMCLIENT_")?.to_ascii_lowercase();
+                Some((key, value))
+            })
+            .collect::<Vec<_>>();
+        let args = parse_args(std::env::args().skip(1));
+        Self::from_entries(file.into_iter().chain(env).chain(args))
+    }
+
+    // Later entries override earlier ones. The world bounds are only checked
+    // once all of them are in, since moving both may pass through a bad span.
+    fn from_entries(entries: impl IntoIterator<Item = (String, String)>) -> Self {
+        let mut config = Config::default();
+        for (key, value) in entries {
+            if !config.set(&key, &value) {
+                println!("Ignoring config entry {key} = {value}");
+            }
+        }
+        let quantization = &mut config.quantization;
+        if (0..3).any(|axis| quantization.world_min[axis] >= quantization.world_max[axis]) {
+            println!(
+                "Ignoring world bounds {:?} to {:?}, the minimum has to be below the maximum",
+                quantization.world_min, quantization.world_max
+            );
+            let defaults = Quantization::default();
+            quantization.world_min = defaults.world_min;
+            quantization.world_max = defaults.world_max;
+        }
+        config
+    }
+
+    // The host may be a name, an IPv4 address or an IPv6 address with or
+    // without brackets; `server` takes a whole `host:port` at once.
+    pub fn endpoint(&self) -> (&str, u16) {
+        (self.host.trim_start_matches('[').trim_end_matches(']'), self.port)
+    }
+
+    // A CA or a pin is only ever set to talk TLS, so either one turns it on
+    // instead of leaving the connection in plain text.
+    pub fn uses_tls(&self) -> bool {
+        self.tls || self.tls_ca.is_some() || self.tls_fingerprint.is_some()
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "server" => match parse_server(value) {
+                Some((host, port)) => {
+                    self.host = host;
+                    self.port = port;
+                    true
+                }
+                None => false,
+            },
+            "host" => set_parsed(
+                &mut self.host,
+                Some(value.to_string()).filter(|host| !host.is_empty()),
+            ),
+            "port" => set_parsed(&mut self.port, value.parse().ok()),
+            "transport" => set_parsed(&mut self.transport, Transport::parse(value)),
+            "nickname" => set_parsed(
+                &mut self.nickname,
+                Some(value.to_string()).filter(|_| validate_nickname(value).is_ok()),
+            ),
+            "world_min" => set_parsed(&mut self.quantization.world_min, parse_vector(value)),
+            "world_max" => set_parsed(&mut self.quantization.world_max, parse_vector(value)),
+            "position_bits" => set_parsed(
+                &mut self.quantization.position_bits,
+                parse_bits(value, MAX_POSITION_BITS),
+            ),
+            "rotation_bits" => set_parsed(
+                &mut self.quantization.rotation_bits,
+                parse_bits(value, MAX_ROTATION_BITS),
+            ),
+            "tick_rate" => set_parsed(&mut self.movement.tick_rate, parse_positive(value)),
+            "rotation_sensitivity" => {
+                set_parsed(&mut self.movement.rotation_sensitivity, parse_positive(value))
+            }
+            "heartbeat_interval" => {
+                set_parsed(&mut self.heartbeat_interval, parse_millis(value, POSITIVE))
+            }
+            "silence_timeout" => {
+                set_parsed(&mut self.silence_timeout, parse_millis(value, POSITIVE))
+            }
+            "interpolation_delay" => {
+                set_parsed(&mut self.interpolation_delay, parse_millis(value, POSITIVE))
+            }
+            "record" => set_parsed(&mut self.record, parse_path(value)),
+            "play" => set_parsed(&mut self.play, parse_path(value)),
+            "tls" => set_parsed(&mut self.tls, parse_bool(value)),
+            "tls_ca" => set_parsed(&mut self.tls_ca, parse_path(value)),
+            "tls_fingerprint" => set_parsed(
+                &mut self.tls_fingerprint,
+                parse_fingerprint(value).map(Some),
+            ),
+            "tls_name" => set_parsed(&mut self.tls_name, parse_path(value)),
+            "require_challenge_auth" => {
+                set_parsed(&mut self.require_challenge_auth, parse_bool(value))
+            }
+            _ => key
+                .strip_prefix("net_")
+                .is_some_and(|key| self.net.set(key, value)),
+        }
+    }
+}
+
+// Intervals and timeouts, where zero would make no sense.
+const POSITIVE: RangeInclusive<Duration> = Duration::from_millis(1)..=Duration::MAX;
+
+pub fn set_parsed<T>(field: &mut T, value: Option<T>) -> bool {
+    value.map(|value| *field = value).is_some()
+}
+
+fn parse_server(value: &str) -> Option<(String, u16)> {
+    if let Ok(address) = value.parse::<SocketAddr>() {
+        return Some((address.ip().to_string(), address.port()));
+    }
+    if value.parse::<IpAddr>().is_ok() {
+        return Some((value.to_string(), DEFAULT_PORT));
+    }
+    match value.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => Some((host.to_string(), port.parse().ok()?)),
+        Some(_) => None,
+        None if !value.is_empty() => Some((value.to_string(), DEFAULT_PORT)),
+        None => None,
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Vec<(String, String)> {
+    let mut values = Vec::new();
+    while let Some(arg) = args.next() {
+        let Some(arg) = arg.strip_prefix("--") else {
+            println!("Ignoring argument {arg}");
+            continue;
+        };
+        let (key, value) = match arg.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => (arg.to_string(), args.next().unwrap_or_default()),
+        };
+        values.push((key.replace('-', "_"), value));
+    }
+    values
+}
+
+fn parse_path(value: &str) -> Option<Option<String>> {
+    (!value.is_empty()).then(|| Some(value.to_string()))
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Some(true),
+        "false" | "no" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+// Hex, optionally with colons between the bytes as `openssl x509
+// -fingerprint -sha256` prints it.
+fn parse_fingerprint(value: &str) -> Option<[u8; 32]> {
+    let hex = value.replace(':', "");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut fingerprint = [0; 32];
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(fingerprint)
+}
+
+fn parse_vector(value: &str) -> Option<[f32; 3]> {
+    let values = value
+        .split(',')
+        .map(|x| x.trim().parse().ok().filter(|x: &f32| x.is_finite()))
+        .collect::<Option<Vec<f32>>>()?;
+    values.try_into().ok()
+}
+
+fn parse_positive(value: &str) -> Option<f32> {
+    value.parse().ok().filter(|x: &f32| x.is_finite() && *x > 0.0)
+}
+
+fn parse_bits(value: &str, max: u8) -> Option<u8> {
+    value.parse().ok().filter(|bits| (1..=max).contains(bits))
+}
+
+// Durations are given in milliseconds and have to fall within `range`.
+pub fn parse_millis(value: &str, range: RangeInclusive<Duration>) -> Option<Duration> {
+    let duration = Duration::from_millis(value.parse().ok()?);
+    range.contains(&duration).then_some(duration)
+}
+
+fn parse_file(file: &str) -> Vec<(String, String)> {
+    file.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn parses_file_entries() {
+        let file = "# client settings\n\nHost = example.org \nport=9100 # local\nno value\n";
+        assert_eq!(parse_file(file), entries(&[("host", "example.org"), ("port", "9100")]));
+    }
+
+    #[test]
+    fn parses_both_argument_forms() {
+        let args = ["--net-latency", "80", "stray", "--nickname=ana", "--tls"];
+        assert_eq!(
+            parse_args(args.into_iter().map(String::from)),
+            entries(&[("net_latency", "80"), ("nickname", "ana"), ("tls", "")])
+        );
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let file = entries(&[("host", "file.org"), ("port", "1000"), ("nickname", "ana")]);
+        let env = entries(&[("port", "2000"), ("nickname", "bo")]);
+        let args = entries(&[("nickname", "cy"), ("port", "not a port")]);
+        let config = Config::from_entries(file.into_iter().chain(env).chain(args));
+        assert_eq!(config.host, "file.org");
+        assert_eq!(config.port, 2000);
+        assert_eq!(config.nickname, "cy");
+    }
+
+    #[test]
+    fn keeps_world_bounds_that_pass_through_a_bad_span() {
+        let config = Config::from_entries(entries(&[
+            ("world_min", "600, 0, 600"),
+            ("world_max", "700, 10, 700"),
+        ]));
+        assert_eq!(config.quantization.world_min, [600.0, 0.0, 600.0]);
+        assert_eq!(config.quantization.world_max, [700.0, 10.0, 700.0]);
+    }
+
+    #[test]
+    fn rejects_an_empty_or_inverted_world_span() {
+        let default = Quantization::default();
+        for max in ["0, 10, 10", "10, -1, 10"] {
+            let config = Config::from_entries(entries(&[
+                ("world_min", "0, 0, 0"),
+                ("world_max", max),
+            ]));
+            assert_eq!(config.quantization.world_min, default.world_min);
+            assert_eq!(config.quantization.world_max, default.world_max);
+        }
+        assert_eq!(parse_vector("0, nan, 1"), None);
+        assert_eq!(parse_vector("0, 1"), None);
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use crate::config::Config;
+
+// The stream to the server, plain or encrypted. Everything above the socket
+// only reads and writes frames, so it works on either.
+pub trait Connection: AsyncRead + AsyncWrite + Send + Unpin {
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+pub type Stream = Box<dyn Connection>;
+
+impl Connection for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
+
+impl Connection for TlsStream<TcpStream> {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.get_ref().0.peer_addr()
+    }
+}
+
+// With TLS on, the server certificate has to either chain up to a CA from
+// `tls_ca` or hash to `tls_fingerprint`, the SHA-256 of its DER encoding.
+// The pin is meant for self-signed test servers, so it skips the name and
+// expiry checks a CA would need. Setting both is refused rather than picking
+// one. Only this stream is encrypted; the UDP channel stays plain text.
+pub async fn secure(stream: TcpStream, config: &Config) -> io::Result<Stream> {
+    if !config.uses_tls() {
+        return Ok(Box::new(stream));
+    }
+    let tls_config = match (&config.tls_ca, config.tls_fingerprint) {
+        (Some(_), Some(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tls_ca and tls_fingerprint cannot both be set",
+            ))
+        }
+        (None, Some(fingerprint)) => ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate(fingerprint)))
+            .with_no_client_auth(),
+        (Some(path), None) => ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(path)?)
+            .with_no_client_auth(),
+        (None, None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS needs either tls_ca or tls_fingerprint",
+            ))
+        }
+    };
+    let name = config.tls_name.as_deref().unwrap_or(config.endpoint().0);
+    let name = ServerName::try_from(name).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{name} is not a valid TLS name"))
+    })?;
+    let connector = TlsConnector::from(Arc::new(tls_config));
+    Ok(Box::new(connector.connect(name, stream).await?))
+}
+
+fn load_roots(path: &str) -> io::Result<RootCertStore> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut roots = RootCertStore::empty();
+    for certificate in rustls_pemfile::certs(&mut reader)? {
+        roots
+            .add(&Certificate(certificate))
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{err:?}")))?;
+    }
+    if roots.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{path} holds no certificates"),
+        ));
+    }
+    Ok(roots)
+}
+
+struct PinnedCertificate([u8; 32]);
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(&end_entity.0).as_slice() == self.0 {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "certificate does not match the pinned fingerprint".into(),
+            ))
+        }
+    }
+}
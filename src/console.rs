@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+
+use crate::netsim::{NetConditions, SharedConditions};
+
+const MAX_OUTPUT: usize = 8;
+
+// A one line command prompt for tweaking the client while it runs.
+#[derive(Default)]
+pub struct Console {
+    pub open: bool,
+    pub input: String,
+    output: VecDeque<String>,
+}
+
+impl Console {
+    // The key that toggles the console is typed as a character too.
+    pub fn push(&mut self, c: char) {
+        if !c.is_control() && c != '`' {
+            self.input.push(c);
+        }
+    }
+
+    pub fn print(&mut self, line: String) {
+        self.output.push_back(line);
+        if self.output.len() > MAX_OUTPUT {
+            self.output.pop_front();
+        }
+    }
+
+    pub fn output(&self) -> impl Iterator<Item = &String> {
+        self.output.iter()
+    }
+
+    pub fn submit(&mut self, conditions: &SharedConditions) {
+        let line = std::mem::take(&mut self.input);
+        let line = line.trim();
+        if line.is_empty() {
+            return;
+        }
+        self.print(format!("> {line}"));
+        let output = execute(line, conditions);
+        self.print(output);
+    }
+}
+
+fn execute(line: &str, conditions: &SharedConditions) -> String {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    match words.as_slice() {
+        ["net"] => conditions.lock().unwrap().describe(),
+        ["net", "off"] => {
+            *conditions.lock().unwrap() = NetConditions::default();
+            "Network simulation off".to_string()
+        }
+        ["net", key, value] => {
+            let mut conditions = conditions.lock().unwrap();
+            if conditions.set(key, value) {
+                conditions.describe()
+            } else {
+                format!("Invalid value {value} for {key}")
+            }
+        }
+        _ => "Commands: net, net off, net <latency|jitter|loss|duplicate|bandwidth> <value>"
+            .to_string(),
+    }
+}
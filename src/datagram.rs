@@ -0,0 +1,126 @@
+use std::io;
+use std::net::SocketAddr;
+
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::codec::{
+    self, read_raw_frame, write_frame, FrameError, Message, MessageKind, RawFrame, REPLY_TIMEOUT,
+};
+use crate::connection::Stream;
+use crate::session::{ServerRequest, UdpBindResponse};
+
+pub const TOKEN_SIZE: usize = 8;
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+// Real-time traffic for a session bound over TCP. Every datagram starts with
+// the session token the server handed out, followed by one regular frame.
+// Datagrams are never encrypted, even when the TCP stream uses TLS.
+pub struct DatagramChannel {
+    socket: UdpSocket,
+    token: u64,
+}
+
+impl DatagramChannel {
+    // Returns `Ok(None)` when the server refuses to open a UDP channel, in
+    // which case the session keeps running over TCP.
+    pub async fn open(stream: &mut Stream) -> Result<Option<Self>, FrameError> {
+        write_frame(stream, &ServerRequest::BindUdp).await?;
+        let binding = match read_binding(stream).await? {
+            UdpBindResponse::Ok(binding) => binding,
+            UdpBindResponse::Err(reason) => {
+                eprintln!("Server refused the UDP channel: {reason}");
+                return Ok(None);
+            }
+        };
+        let server = stream.peer_addr()?;
+        let local: SocketAddr = if server.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect((server.ip(), binding.port)).await?;
+        Ok(Some(Self {
+            socket,
+            token: binding.token,
+        }))
+    }
+
+    pub async fn send<M: Message>(&self, message: &M) -> Result<(), FrameError> {
+        self.send_frame(&codec::encode(message)?).await
+    }
+
+    pub async fn send_frame(&self, frame: &[u8]) -> Result<(), FrameError> {
+        let mut datagram = self.token.to_be_bytes().to_vec();
+        datagram.extend_from_slice(frame);
+        if datagram.len() > MAX_DATAGRAM_SIZE {
+            return Err(FrameError::TooLarge(datagram.len()));
+        }
+        match self.socket.send(&datagram).await {
+            Err(err) if !is_refused(&err) => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    // Datagrams that are stale, foreign or malformed are skipped rather than
+    // treated as a broken connection; only socket errors are returned.
+    pub async fn recv(&self) -> Result<RawFrame, io::Error> {
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            let len = match self.socket.recv(&mut buf).await {
+                Ok(len) => len,
+                Err(err) if is_refused(&err) => continue,
+                Err(err) => return Err(err),
+            };
+            match self.decode(&buf[..len]) {
+                Ok(Some(frame)) => return Ok(frame),
+                Ok(None) => continue,
+                Err(err) => println!("Dropping datagram: {err}"),
+            }
+        }
+    }
+
+    fn decode(&self, datagram: &[u8]) -> Result<Option<RawFrame>, FrameError> {
+        if datagram.len() < TOKEN_SIZE {
+            return Ok(None);
+        }
+        let (token, frame) = datagram.split_at(TOKEN_SIZE);
+        if u64::from_be_bytes(token.try_into().unwrap()) != self.token {
+            return Ok(None);
+        }
+        match codec::decode_raw(frame)? {
+            Some((frame, used)) if used == datagram.len() - TOKEN_SIZE => Ok(Some(frame)),
+            Some((_, used)) => Err(FrameError::TrailingBytes(datagram.len() - TOKEN_SIZE - used)),
+            None => Ok(None),
+        }
+    }
+}
+
+async fn read_binding(stream: &mut Stream) -> Result<UdpBindResponse, FrameError> {
+    match timeout(REPLY_TIMEOUT, skip_to_binding(stream)).await {
+        Ok(result) => result,
+        Err(_) => Err(FrameError::Io(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "the server did not reply in time",
+        ))),
+    }
+}
+
+// The session is already running, so snapshots sent before the server got
+// the request come first; they are dropped, the next ones are only a tick away.
+async fn skip_to_binding(stream: &mut Stream) -> Result<UdpBindResponse, FrameError> {
+    loop {
+        let frame = read_raw_frame(stream).await?;
+        if frame.kind == MessageKind::UdpBindResponse {
+            return frame.decode();
+        }
+    }
+}
+
+// A connected UDP socket reports an ICMP port unreachable as a refused
+// connection on its next call. That only means a datagram got lost; a server
+// that is really gone is noticed by the TCP stream or the silence timeout.
+fn is_refused(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::ConnectionRefused
+}
@@ -0,0 +1,593 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use deku::ctx::Endian;
+use deku::prelude::*;
+
+use crate::codec::{wire_len, MAX_WIRE_ITEMS, WIRE_ENDIAN};
+use crate::network::{RemotePlayer, ResponseSignal};
+use crate::objects::NetworkObject;
+
+pub const TRANSLATION_CHANGED: u8 = 1 << 0;
+pub const CAMERA_POS_CHANGED: u8 = 1 << 1;
+pub const CAMERA_TARGET_CHANGED: u8 = 1 << 2;
+pub const FWD_CHANGED: u8 = 1 << 3;
+pub const RIGHT_CHANGED: u8 = 1 << 4;
+
+pub const POSITION_CHANGED: u8 = 1 << 0;
+pub const ROTATION_CHANGED: u8 = 1 << 1;
+pub const TEAM_CHANGED: u8 = 1 << 2;
+
+const BASELINE_HISTORY: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
+pub enum SnapshotMessage {
+    #[deku(id = "0x1")]
+    Full { sequence: u32, state: ResponseSignal },
+    #[deku(id = "0x2")]
+    Delta(DeltaSnapshot),
+}
+
+#[derive(Clone, Debug, Default, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct StateDelta {
+    pub changed: u8,
+    #[deku(cond = "*changed & TRANSLATION_CHANGED != 0")]
+    pub translation: Option<[f32; 3]>,
+    #[deku(cond = "*changed & CAMERA_POS_CHANGED != 0")]
+    pub camera_pos: Option<[f32; 3]>,
+    #[deku(cond = "*changed & CAMERA_TARGET_CHANGED != 0")]
+    pub camera_target: Option<[f32; 3]>,
+    #[deku(cond = "*changed & FWD_CHANGED != 0")]
+    pub fwd: Option<[f32; 3]>,
+    #[deku(cond = "*changed & RIGHT_CHANGED != 0")]
+    pub right: Option<[f32; 3]>,
+}
+
+impl StateDelta {
+    pub fn between(from: &ResponseSignal, to: &ResponseSignal) -> Self {
+        let mut changed = 0;
+        let translation = diff(&mut changed, TRANSLATION_CHANGED, from.translation, to.translation);
+        let camera_pos = diff(&mut changed, CAMERA_POS_CHANGED, from.camera_pos, to.camera_pos);
+        let camera_target = diff(
+            &mut changed,
+            CAMERA_TARGET_CHANGED,
+            from.camera_target,
+            to.camera_target,
+        );
+        let fwd = diff(&mut changed, FWD_CHANGED, from.fwd, to.fwd);
+        let right = diff(&mut changed, RIGHT_CHANGED, from.right, to.right);
+        Self {
+            changed,
+            translation,
+            camera_pos,
+            camera_target,
+            fwd,
+            right,
+        }
+    }
+
+    pub fn apply(&self, state: &mut ResponseSignal) {
+        apply(&mut state.translation, self.translation);
+        apply(&mut state.camera_pos, self.camera_pos);
+        apply(&mut state.camera_target, self.camera_target);
+        apply(&mut state.fwd, self.fwd);
+        apply(&mut state.right, self.right);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct ObjectDelta {
+    pub index: u16,
+    pub changed: u8,
+    #[deku(cond = "*changed & POSITION_CHANGED != 0")]
+    pub position: Option<[f32; 3]>,
+    #[deku(cond = "*changed & ROTATION_CHANGED != 0")]
+    pub rotation: Option<[f32; 4]>,
+}
+
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct PlayerDelta {
+    pub id: u32,
+    pub changed: u8,
+    #[deku(cond = "*changed & POSITION_CHANGED != 0")]
+    pub position: Option<[f32; 3]>,
+    #[deku(cond = "*changed & ROTATION_CHANGED != 0")]
+    pub rotation: Option<[f32; 4]>,
+    #[deku(cond = "*changed & TEAM_CHANGED != 0")]
+    pub team: Option<u8>,
+}
+
+// Everything that changed between the acknowledged `baseline` snapshot and
+// `sequence`. Players are matched by id, and a player whose nickname changed
+// is sent again in full. Objects are referenced by their index in the
+// baseline list so ids are only sent for objects the client has not seen yet.
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct DeltaSnapshot {
+    pub sequence: u32,
+    pub baseline: u32,
+    pub last_input: u32,
+    pub state: StateDelta,
+    #[deku(
+        update = "self.changed_players.len()",
+        assert = "*changed_player_count <= MAX_WIRE_ITEMS"
+    )]
+    changed_player_count: u16,
+    #[deku(count = "changed_player_count")]
+    pub changed_players: Vec<PlayerDelta>,
+    #[deku(update = "self.added_players.len()", assert = "*added_player_count <= MAX_WIRE_ITEMS")]
+    added_player_count: u16,
+    #[deku(count = "added_player_count")]
+    pub added_players: Vec<RemotePlayer>,
+    #[deku(
+        update = "self.removed_players.len()",
+        assert = "*removed_player_count <= MAX_WIRE_ITEMS"
+    )]
+    removed_player_count: u16,
+    #[deku(count = "removed_player_count")]
+    pub removed_players: Vec<u32>,
+    #[deku(update = "self.changed_objects.len()", assert = "*changed_count <= MAX_WIRE_ITEMS")]
+    changed_count: u16,
+    #[deku(count = "changed_count")]
+    pub changed_objects: Vec<ObjectDelta>,
+    #[deku(update = "self.added_objects.len()", assert = "*added_count <= MAX_WIRE_ITEMS")]
+    added_count: u16,
+    #[deku(count = "added_count")]
+    pub added_objects: Vec<NetworkObject>,
+    #[deku(update = "self.removed_objects.len()", assert = "*removed_count <= MAX_WIRE_ITEMS")]
+    removed_count: u16,
+    #[deku(count = "removed_count")]
+    pub removed_objects: Vec<u16>,
+}
+
+impl DeltaSnapshot {
+    pub fn between(
+        sequence: u32,
+        baseline_sequence: u32,
+        baseline: &ResponseSignal,
+        current: &ResponseSignal,
+    ) -> Self {
+        let baseline_players = baseline
+            .players
+            .iter()
+            .map(|player| (player.id, player))
+            .collect::<HashMap<_, _>>();
+        let mut changed_players = Vec::new();
+        let mut added_players = Vec::new();
+        for player in current.players.iter() {
+            let Some(from) = baseline_players
+                .get(&player.id)
+                .filter(|from| from.nickname == player.nickname)
+            else {
+                added_players.push(player.clone());
+                continue;
+            };
+            let mut changed = 0;
+            let position = diff(&mut changed, POSITION_CHANGED, from.position, player.position);
+            let rotation = diff(&mut changed, ROTATION_CHANGED, from.rotation, player.rotation);
+            let team = diff(&mut changed, TEAM_CHANGED, from.team, player.team);
+            if changed != 0 {
+                changed_players.push(PlayerDelta {
+                    id: player.id,
+                    changed,
+                    position,
+                    rotation,
+                    team,
+                });
+            }
+        }
+        let removed_players = baseline
+            .players
+            .iter()
+            .filter(|from| !current.players.iter().any(|player| player.id == from.id))
+            .map(|from| from.id)
+            .collect::<Vec<_>>();
+
+        let indices = baseline
+            .objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.id.as_slice(), i))
+            .collect::<HashMap<_, _>>();
+        let mut changed_objects = Vec::new();
+        let mut added_objects = Vec::new();
+        for object in current.objects.iter() {
+            let Some(&index) = indices.get(object.id.as_slice()) else {
+                added_objects.push(object.clone());
+                continue;
+            };
+            let from = &baseline.objects[index];
+            let mut changed = 0;
+            let position = diff(&mut changed, POSITION_CHANGED, from.position, object.position);
+            let rotation = diff(&mut changed, ROTATION_CHANGED, from.rotation, object.rotation);
+            if changed != 0 {
+                changed_objects.push(ObjectDelta {
+                    index: index as u16,
+                    changed,
+                    position,
+                    rotation,
+                });
+            }
+        }
+        let current_ids = current
+            .objects
+            .iter()
+            .map(|object| object.id.as_slice())
+            .collect::<Vec<_>>();
+        let removed_objects = baseline
+            .objects
+            .iter()
+            .enumerate()
+            .filter(|(_, object)| !current_ids.contains(&object.id.as_slice()))
+            .map(|(i, _)| i as u16)
+            .collect::<Vec<_>>();
+
+        Self {
+            sequence,
+            baseline: baseline_sequence,
+            last_input: current.last_input,
+            state: StateDelta::between(baseline, current),
+            changed_player_count: wire_len(changed_players.len()),
+            changed_players,
+            added_player_count: wire_len(added_players.len()),
+            added_players,
+            removed_player_count: wire_len(removed_players.len()),
+            removed_players,
+            changed_count: wire_len(changed_objects.len()),
+            changed_objects,
+            added_count: wire_len(added_objects.len()),
+            added_objects,
+            removed_count: wire_len(removed_objects.len()),
+            removed_objects,
+        }
+    }
+
+    pub fn apply(&self, baseline: &ResponseSignal) -> Result<ResponseSignal, DeltaError> {
+        let mut state = baseline.clone();
+        state.last_input = self.last_input;
+        self.state.apply(&mut state);
+
+        let mut players = baseline.players.clone();
+        for change in self.changed_players.iter() {
+            let player = players
+                .iter_mut()
+                .find(|player| player.id == change.id)
+                .ok_or(DeltaError::UnknownPlayer(change.id))?;
+            apply(&mut player.position, change.position);
+            apply(&mut player.rotation, change.rotation);
+            apply(&mut player.team, change.team);
+        }
+        // Added players may replace a baseline entry whose nickname changed.
+        players.retain(|player| {
+            !self.removed_players.contains(&player.id)
+                && !self.added_players.iter().any(|added| added.id == player.id)
+        });
+        players.extend(self.added_players.iter().cloned());
+        state.players = players;
+
+        let mut objects = baseline.objects.iter().cloned().map(Some).collect::<Vec<_>>();
+        for change in self.changed_objects.iter() {
+            let object = objects
+                .get_mut(change.index as usize)
+                .and_then(Option::as_mut)
+                .ok_or(DeltaError::UnknownObject(change.index))?;
+            apply(&mut object.position, change.position);
+            apply(&mut object.rotation, change.rotation);
+        }
+        for &index in self.removed_objects.iter() {
+            match objects.get_mut(index as usize) {
+                Some(object) => *object = None,
+                None => return Err(DeltaError::UnknownObject(index)),
+            }
+        }
+        state.objects = objects
+            .into_iter()
+            .flatten()
+            .chain(self.added_objects.iter().cloned())
+            .collect();
+        state.player_count = wire_len(state.players.len());
+        state.object_count = wire_len(state.objects.len());
+        Ok(state)
+    }
+}
+
+#[derive(Debug)]
+pub enum DeltaError {
+    UnknownBaseline(u32),
+    UnknownPlayer(u32),
+    UnknownObject(u16),
+}
+
+impl fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaError::UnknownBaseline(sequence) => {
+                write!(f, "delta against snapshot {sequence} which is no longer kept")
+            }
+            DeltaError::UnknownPlayer(id) => write!(f, "delta references unknown player {id}"),
+            DeltaError::UnknownObject(index) => write!(f, "delta references unknown object {index}"),
+        }
+    }
+}
+
+impl std::error::Error for DeltaError {}
+
+// The last few rebuilt snapshots, any of which the server may pick as the
+// baseline for a delta once it has seen the client acknowledge it.
+#[derive(Default)]
+pub struct BaselineHistory {
+    snapshots: VecDeque<(u32, ResponseSignal)>,
+    acked: u32,
+}
+
+impl BaselineHistory {
+    pub fn acked(&self) -> u32 {
+        self.acked
+    }
+
+    // A resumed session may start its sequence numbers over, and none of the
+    // old baselines are any use to the new connection.
+    pub fn reset(&mut self) {
+        self.snapshots.clear();
+        self.acked = 0;
+    }
+
+    // Returns `Ok(None)` for snapshots no newer than the last one. Over UDP
+    // they can arrive late or twice, and handing them on would make remote
+    // players step backwards.
+    pub fn receive(
+        &mut self,
+        message: SnapshotMessage,
+    ) -> Result<Option<ResponseSignal>, DeltaError> {
+        let sequence = match &message {
+            SnapshotMessage::Full { sequence, .. } => *sequence,
+            SnapshotMessage::Delta(delta) => delta.sequence,
+        };
+        if self.snapshots.back().is_some_and(|(newest, _)| sequence <= *newest) {
+            return Ok(None);
+        }
+        let state = match message {
+            SnapshotMessage::Full { state, .. } => state,
+            SnapshotMessage::Delta(delta) => {
+                let baseline = self
+                    .snapshots
+                    .iter()
+                    .find(|(sequence, _)| *sequence == delta.baseline)
+                    .ok_or(DeltaError::UnknownBaseline(delta.baseline))?;
+                delta.apply(&baseline.1)?
+            }
+        };
+        self.acked = sequence;
+        self.snapshots.push_back((sequence, state.clone()));
+        if self.snapshots.len() > BASELINE_HISTORY {
+            self.snapshots.pop_front();
+        }
+        Ok(Some(state))
+    }
+}
+
+fn diff<T: PartialEq + Copy>(changed: &mut u8, flag: u8, from: T, to: T) -> Option<T> {
+    if from == to {
+        return None;
+    }
+    *changed |= flag;
+    Some(to)
+}
+
+fn apply<T: Copy>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::codec::testing::{frame_round_trip, response_signal};
+
+    fn player(id: u32, nickname: &str, x: f32, team: u8) -> RemotePlayer {
+        RemotePlayer::new(id, nickname.into(), [x, 1.0, 2.0], [0.0, 0.0, 0.0, 1.0], team)
+    }
+
+    fn object(id: &str, x: f32) -> NetworkObject {
+        NetworkObject::new([x, 0.0, -x], [0.0, 1.0, 0.0, 0.0], id.into())
+    }
+
+    fn signal(players: Vec<RemotePlayer>, objects: Vec<NetworkObject>) -> ResponseSignal {
+        let mut signal = ResponseSignal::default();
+        signal.player_count = wire_len(players.len());
+        signal.object_count = wire_len(objects.len());
+        signal.players = players;
+        signal.objects = objects;
+        signal
+    }
+
+    fn baseline() -> ResponseSignal {
+        signal(
+            vec![player(1, "ana", 0.0, 0), player(2, "bo", 1.0, 0), player(3, "cy", 2.0, 1)],
+            vec![object("crate", 0.0), object("door", 1.0), object("lift", 2.0)],
+        )
+    }
+
+    #[test]
+    fn unchanged_state_sends_nothing() {
+        let baseline = baseline();
+        let delta = DeltaSnapshot::between(2, 1, &baseline, &baseline);
+        assert_eq!(delta.state.changed, 0);
+        assert!(delta.changed_players.is_empty() && delta.added_players.is_empty());
+        assert!(delta.changed_objects.is_empty() && delta.added_objects.is_empty());
+        assert_eq!(delta.apply(&baseline).unwrap(), baseline);
+    }
+
+    #[test]
+    fn rebuilds_added_removed_and_changed_entities() {
+        let baseline = baseline();
+        let mut current = signal(
+            vec![player(1, "ana", 5.0, 1), player(3, "cy", 2.0, 1), player(4, "di", 3.0, 0)],
+            vec![object("crate", 4.0), object("lift", 2.0), object("ramp", 7.0)],
+        );
+        current.last_input = 42;
+        current.translation = [1.0, 2.0, 3.0];
+        current.fwd = [1.0, 0.0, 0.0];
+        let delta = DeltaSnapshot::between(2, 1, &baseline, &current);
+        assert_eq!(delta.changed_players.len(), 1);
+        assert_eq!(delta.added_players.len(), 1);
+        assert_eq!(delta.removed_players, vec![2]);
+        assert_eq!(delta.changed_objects.len(), 1);
+        assert_eq!(delta.added_objects.len(), 1);
+        assert_eq!(delta.removed_objects, vec![1]);
+        assert_eq!(delta.state.changed, TRANSLATION_CHANGED | FWD_CHANGED);
+        assert_eq!(delta.apply(&baseline).unwrap(), current);
+    }
+
+    #[test]
+    fn sends_a_renamed_player_in_full() {
+        let baseline = baseline();
+        let current = signal(
+            vec![player(1, "ana", 0.0, 0), player(2, "bob", 1.0, 0), player(3, "cy", 2.0, 1)],
+            baseline.objects.clone(),
+        );
+        let delta = DeltaSnapshot::between(2, 1, &baseline, &current);
+        assert!(delta.changed_players.is_empty());
+        assert_eq!(delta.added_players, vec![player(2, "bob", 1.0, 0)]);
+        let rebuilt = delta.apply(&baseline).unwrap();
+        let mut names = rebuilt.players.iter().map(RemotePlayer::nickname).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["ana", "bob", "cy"]);
+        assert_eq!(rebuilt.player_count, 3);
+    }
+
+    #[test]
+    fn snapshot_messages_round_trip() {
+        let baseline = baseline();
+        let current = signal(
+            vec![player(1, "ana", 5.0, 1), player(4, "di", 3.0, 0)],
+            vec![object("crate", 4.0), object("ramp", 7.0)],
+        );
+        let messages = [
+            SnapshotMessage::Full {
+                sequence: 1,
+                state: baseline.clone(),
+            },
+            SnapshotMessage::Delta(DeltaSnapshot::between(2, 1, &baseline, &current)),
+        ];
+        for message in messages {
+            let bytes = message.to_bytes().unwrap();
+            let ((rest, _), decoded) = SnapshotMessage::from_bytes((bytes.as_slice(), 0)).unwrap();
+            assert!(rest.is_empty());
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_references() {
+        let baseline = baseline();
+        let mut history = BaselineHistory::default();
+        let delta = DeltaSnapshot::between(5, 4, &baseline, &baseline);
+        assert!(matches!(
+            history.receive(SnapshotMessage::Delta(delta)),
+            Err(DeltaError::UnknownBaseline(4))
+        ));
+
+        let mut delta = DeltaSnapshot::between(2, 1, &baseline, &baseline);
+        delta.changed_objects.push(ObjectDelta {
+            index: 3,
+            changed: POSITION_CHANGED,
+            position: Some([1.0, 1.0, 1.0]),
+            rotation: None,
+        });
+        assert!(matches!(delta.apply(&baseline), Err(DeltaError::UnknownObject(3))));
+
+        let mut delta = DeltaSnapshot::between(2, 1, &baseline, &baseline);
+        delta.removed_objects.push(9);
+        assert!(matches!(delta.apply(&baseline), Err(DeltaError::UnknownObject(9))));
+
+        let mut delta = DeltaSnapshot::between(2, 1, &baseline, &baseline);
+        delta.changed_players.push(PlayerDelta {
+            id: 7,
+            changed: TEAM_CHANGED,
+            position: None,
+            rotation: None,
+            team: Some(1),
+        });
+        assert!(matches!(delta.apply(&baseline), Err(DeltaError::UnknownPlayer(7))));
+    }
+
+    #[test]
+    fn applies_deltas_against_acknowledged_baselines() {
+        let baseline = baseline();
+        let current = signal(vec![player(1, "ana", 9.0, 0)], Vec::new());
+        let mut history = BaselineHistory::default();
+        let full = SnapshotMessage::Full {
+            sequence: 1,
+            state: baseline.clone(),
+        };
+        assert_eq!(history.receive(full).unwrap(), Some(baseline.clone()));
+        let delta = DeltaSnapshot::between(2, 1, &baseline, &current);
+        assert_eq!(history.receive(SnapshotMessage::Delta(delta)).unwrap(), Some(current));
+        assert_eq!(history.acked(), 2);
+    }
+
+    #[test]
+    fn ignores_late_and_duplicate_snapshots() {
+        let mut history = BaselineHistory::default();
+        let full = |sequence| SnapshotMessage::Full {
+            sequence,
+            state: baseline(),
+        };
+        assert!(history.receive(full(3)).unwrap().is_some());
+        assert!(history.receive(full(3)).unwrap().is_none());
+        assert!(history.receive(full(2)).unwrap().is_none());
+        let delta = DeltaSnapshot::between(1, 3, &baseline(), &baseline());
+        assert!(history.receive(SnapshotMessage::Delta(delta)).unwrap().is_none());
+        assert_eq!(history.acked(), 3);
+        assert_eq!(history.snapshots.len(), 1);
+        assert!(history.receive(full(4)).unwrap().is_some());
+        assert_eq!(history.acked(), 4);
+    }
+
+    #[test]
+    fn accepts_restarted_sequences_after_a_reset() {
+        let mut history = BaselineHistory::default();
+        let full = |sequence| SnapshotMessage::Full {
+            sequence,
+            state: baseline(),
+        };
+        assert!(history.receive(full(40)).unwrap().is_some());
+        assert!(history.receive(full(1)).unwrap().is_none());
+        history.reset();
+        assert_eq!(history.acked(), 0);
+        assert!(history.receive(full(1)).unwrap().is_some());
+        let delta = DeltaSnapshot::between(2, 40, &baseline(), &baseline());
+        assert!(matches!(
+            history.receive(SnapshotMessage::Delta(delta)),
+            Err(DeltaError::UnknownBaseline(40))
+        ));
+        assert_eq!(history.acked(), 1);
+    }
+
+    proptest! {
+        #[test]
+        fn snapshot_messages_round_trip(
+            sequence in any::<u32>(),
+            baseline in response_signal(),
+            state in response_signal(),
+        ) {
+            let baseline_sequence = sequence.wrapping_sub(1);
+            let delta = DeltaSnapshot::between(sequence, baseline_sequence, &baseline, &state);
+            frame_round_trip(&SnapshotMessage::Delta(delta))?;
+            frame_round_trip(&SnapshotMessage::Full { sequence, state })?;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+use crate::codec::{decode_raw, encode};
+use crate::error::ClientError;
+use crate::network::{ResponseSignal, PROTOCOL_VERSION};
+
+const MAGIC: &[u8; 4] = b"DEMO";
+const HEADER_SIZE: usize = MAGIC.len() + 2;
+const TIMESTAMP_SIZE: usize = 8;
+pub const MIN_SPEED: f32 = 0.25;
+pub const MAX_SPEED: f32 = 4.0;
+
+// A demo file is `DEMO`, the protocol version as a big endian u16, then one
+// record per snapshot: microseconds since recording started as a big endian
+// u64 followed by the snapshot as a regular frame.
+pub struct DemoRecorder {
+    writer: BufWriter<File>,
+    started: Instant,
+}
+
+impl DemoRecorder {
+    pub fn create(path: &str) -> Result<Self, ClientError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&PROTOCOL_VERSION.to_be_bytes())?;
+        Ok(Self {
+            writer,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn record(
+        &mut self,
+        signal: &ResponseSignal,
+        received: Instant,
+    ) -> Result<(), ClientError> {
+        let time = received.saturating_duration_since(self.started).as_micros() as u64;
+        self.writer.write_all(&time.to_be_bytes())?;
+        self.writer.write_all(&encode(signal)?)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), ClientError> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct Demo {
+    frames: Vec<(Duration, ResponseSignal)>,
+}
+
+impl Demo {
+    pub fn load(path: &str) -> Result<Self, ClientError> {
+        let invalid = |reason: &str| ClientError::Asset {
+            name: path.to_string(),
+            reason: reason.to_string(),
+        };
+        let data = std::fs::read(path)?;
+        if data.len() < HEADER_SIZE || &data[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a demo file"));
+        }
+        let version = u16::from_be_bytes([data[MAGIC.len()], data[MAGIC.len() + 1]]);
+        if version != PROTOCOL_VERSION {
+            return Err(invalid(&format!(
+                "recorded with protocol {version}, this client speaks {PROTOCOL_VERSION}"
+            )));
+        }
+        let mut frames = Vec::new();
+        let mut rest = &data[HEADER_SIZE..];
+        while !rest.is_empty() {
+            if rest.len() < TIMESTAMP_SIZE {
+                return Err(invalid("truncated record"));
+            }
+            let (time, frame) = rest.split_at(TIMESTAMP_SIZE);
+            let time = Duration::from_micros(u64::from_be_bytes(time.try_into().unwrap()));
+            let Some((frame, used)) = decode_raw(frame)? else {
+                return Err(invalid("truncated record"));
+            };
+            frames.push((time, frame.decode()?));
+            rest = &rest[TIMESTAMP_SIZE + used..];
+        }
+        if frames.is_empty() {
+            return Err(invalid("no snapshots recorded"));
+        }
+        Ok(Self { frames })
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.frames.last().map(|(time, _)| *time).unwrap_or_default()
+    }
+}
+
+// Walks through a demo at `speed` times real time. Snapshots come out in the
+// order they were recorded; seeking jumps straight to the last snapshot at or
+// before the new position.
+pub struct DemoPlayer {
+    demo: Demo,
+    position: Duration,
+    next: usize,
+    pub speed: f32,
+    pub paused: bool,
+}
+
+impl DemoPlayer {
+    pub fn new(demo: Demo) -> Self {
+        Self {
+            demo,
+            position: Duration::ZERO,
+            next: 0,
+            speed: 1.0,
+            paused: false,
+        }
+    }
+
+    pub fn position(&self) -> Duration {
+        self.position
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.demo.duration()
+    }
+
+    pub fn finished(&self) -> bool {
+        self.next >= self.demo.frames.len()
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(MAX_SPEED);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(MIN_SPEED);
+    }
+
+    // Moves the playhead by `elapsed` wall clock time and returns the
+    // snapshots passed on the way, each with how far behind the playhead it
+    // is in wall clock time.
+    pub fn advance(&mut self, elapsed: Duration) -> Vec<(ResponseSignal, Duration)> {
+        if self.paused {
+            return Vec::new();
+        }
+        self.position = (self.position + elapsed.mul_f32(self.speed)).min(self.duration());
+        let mut passed = Vec::new();
+        while let Some((time, signal)) = self.demo.frames.get(self.next) {
+            if *time > self.position {
+                break;
+            }
+            passed.push((signal.clone(), (self.position - *time).div_f32(self.speed)));
+            self.next += 1;
+        }
+        passed
+    }
+
+    pub fn seek(&mut self, to: Duration) -> Option<ResponseSignal> {
+        self.position = to.min(self.duration());
+        self.next = self
+            .demo
+            .frames
+            .partition_point(|(time, _)| *time <= self.position);
+        let current = self.next.checked_sub(1)?;
+        Some(self.demo.frames[current].1.clone())
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+use crate::codec::FrameError;
+use crate::network::Reason;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    Protocol(FrameError),
+    InvalidText(FromUtf8Error),
+    TooLong { length: usize, limit: usize },
+    Unprintable,
+    PlaintextPassword,
+    Asset { name: String, reason: String },
+    Rejected(Reason),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ClientError::*;
+        match self {
+            Io(err) => write!(f, "I/O error: {err}"),
+            Protocol(err) => write!(f, "{err}"),
+            InvalidText(err) => write!(f, "text is not valid UTF-8: {err}"),
+            TooLong { length, limit } => {
+                write!(f, "text of {length} characters exceeds the {limit} character limit")
+            }
+            Unprintable => write!(f, "text contains control characters"),
+            PlaintextPassword => write!(f, "the server would receive the password in plain text"),
+            Asset { name, reason } => write!(f, "could not load {name}: {reason}"),
+            Rejected(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+// Socket errors surfacing through the codec are reported as plain I/O.
+impl From<FrameError> for ClientError {
+    fn from(err: FrameError) -> Self {
+        match err {
+            FrameError::Io(err) => Self::Io(err),
+            err => Self::Protocol(err),
+        }
+    }
+}
+
+impl From<FromUtf8Error> for ClientError {
+    fn from(err: FromUtf8Error) -> Self {
+        Self::InvalidText(err)
+    }
+}
+
+impl From<Reason> for ClientError {
+    fn from(reason: Reason) -> Self {
+        Self::Rejected(reason)
+    }
+}
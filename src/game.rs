@@ -1,34 +1,76 @@
-use deku::{DekuContainerRead, DekuContainerWrite};
 use raylib::prelude::*;
 use raylib::{camera::Camera3D, drawing::RaylibMode3DExt};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, Join};
 use std::collections::HashMap;
-use tokio::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use crate::auth::{self, VerifiedSessionRequest};
+use crate::browser::{describe, SessionBrowser, SortKey};
+use crate::chat::{ChatChannel, ChatHistory, ChatInput};
+use crate::codec::{read_reply, write_frame};
+use crate::config::Config;
+use crate::connection::Stream;
+use crate::console::Console;
+use crate::demo::{Demo, DemoPlayer, DemoRecorder};
+use crate::error::ClientError;
 use crate::gui::Draw;
-use crate::network::{get_stream, Reason, ResponseSignal, ServerResponse};
+use crate::interpolation::{Snapshot, SnapshotBuffer, Transform};
+use crate::netsim::SharedConditions;
+use crate::network::{
+    get_stream, handshake, NetworkEvent, NetworkTask, Reason, RemotePlayer, ResponseSignal,
+    ServerResponse, Session, CAP_CHALLENGE_AUTH,
+};
 use crate::player::Player;
 use crate::session::*;
-use crate::{lights, network, objects::*};
+use crate::{lights, objects::*};
+
+const MAX_LISTED_SESSIONS: usize = 6;
+const SEEK_STEP: Duration = Duration::from_secs(5);
+const CREATE_DEFAULTS: [(&str, &str); 3] =
+    [("player_limit", "8"), ("map", "scene"), ("round_time", "10")];
 
 #[derive(PartialEq, Eq)]
 enum GameState {
     MainMenu,
     CreateMenu,
     JoinMenu,
+    Browse,
     InGame,
+    Playback,
     ErrorMessage
 }
 
 pub struct GameManager {
-    pub players: Vec<ResponseSignal>,
+    pub players: HashMap<u32, Transform>,
+    pub roster: HashMap<u32, RemotePlayer>,
+    player_id: Option<u32>,
     pub objects: HashMap<String, Object>,
     sky_shader: Shader,
     pub player: Player,
     state: GameState,
     once_game: bool,
     draw: Draw,
-    server_error: Option<Reason>
+    error_message: Option<String>,
+    stream: Option<Stream>,
+    capabilities: u32,
+    network: Option<NetworkTask>,
+    reconnecting: Option<u32>,
+    show_net_stats: bool,
+    menu_open: bool,
+    chat: ChatHistory,
+    chat_input: ChatInput,
+    chat_open: bool,
+    console: Console,
+    conditions: SharedConditions,
+    create_mode: GameMode,
+    create_visibility: Visibility,
+    browser: SessionBrowser,
+    refresh_sessions: bool,
+    interpolation: SnapshotBuffer,
+    recorder: Option<DemoRecorder>,
+    playback: Option<DemoPlayer>,
+    last_frame: Instant,
+    config: Config,
 }
 
 impl GameManager {
@@ -36,7 +78,6 @@ impl GameManager {
         &mut self,
         handle: &mut raylib::RaylibHandle,
         thread: &raylib::RaylibThread,
-        stream: &mut TcpStream,
     ) {
         use GameState::*;
         match self.state {
@@ -44,19 +85,42 @@ impl GameManager {
                 self.draw_main_menu(handle, thread);
             },
             CreateMenu => {
-                self.draw_new_game_menu(handle, thread, stream).await;
+                self.draw_new_game_menu(handle, thread).await;
             },
             JoinMenu => {
-                self.draw_join_game_menu(handle, thread, stream).await;
+                self.draw_join_game_menu(handle, thread).await;
+            }
+            Browse => {
+                self.draw_browser(handle, thread).await;
             }
             InGame => {
                 if !self.once_game {
                     handle.disable_cursor();
                     self.once_game = true
                 }
-                self.do_game_logic(handle, thread, stream).await;
-                self.draw_game(handle, thread);
+                if let Err(err) = self.do_game_logic(handle, thread).await {
+                    self.leave_game(handle);
+                    self.fail(err);
+                    return;
+                }
+                if self.draw_game(handle, thread) {
+                    self.leave_session(handle).await;
+                }
             },
+            Playback => {
+                if !self.once_game {
+                    handle.disable_cursor();
+                    self.once_game = true
+                }
+                if let Err(err) = self.do_playback(handle, thread) {
+                    self.leave_game(handle);
+                    self.fail(err);
+                    return;
+                }
+                if self.draw_game(handle, thread) {
+                    self.leave_game(handle);
+                }
+            }
             ErrorMessage => {
                 self.draw_server_error(handle, thread);
             }
@@ -65,53 +129,180 @@ impl GameManager {
     fn draw_main_menu(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread) {
         let mut handle = clear_screen(handle, thread);
         let handle = &mut handle;
-        
+
+        self.draw.draw_textbox("nickname", handle, [0.0, 12.0]);
         self.draw.draw_label("Jogo Fodakkj", handle, [0.0, 25.0]);
+        self.draw.draw_label("Nickname:", handle, [0.0, 17.0]);
         if self.draw.draw_button("Create Session", handle, [0.0, 0.0]) {
             self.state = GameState::CreateMenu;
+            for (id, default) in CREATE_DEFAULTS {
+                if self.draw.text(id).is_empty() {
+                    self.draw.set_text(id, default);
+                }
+            }
         }
         if self.draw.draw_button("Join Session", handle, [0.0, -15.0]) {
             self.state = GameState::JoinMenu;
         }
+        if self.draw.draw_button("Browse Sessions", handle, [0.0, -30.0]) {
+            self.state = GameState::Browse;
+            self.refresh_sessions = true;
+        }
     }
-    async fn draw_new_game_menu(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread, stream: &mut TcpStream) {
+
+    async fn draw_browser(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread) {
+        if std::mem::take(&mut self.refresh_sessions) && self.ensure_connected().await {
+            if let Err(err) = self.list_sessions().await {
+                self.fail(err);
+                return;
+            }
+        }
         let mut handle = clear_screen(handle, thread);
         let handle = &mut handle;
-        
-        self.draw.draw_label("Create Game", handle, [0.0, 25.0]);
-        self.draw.draw_label("Session ID:", handle, [0.0, 5.0]);
-        self.draw.draw_textbox("id", handle, [0.0, 0.0]);
-        self.draw.draw_label("Session Password:", handle, [0.0, -15.0]);
-        self.draw.draw_textbox("passwd", handle, [0.0, -20.0]);
-        if self.draw.draw_button("Back to Main Menu", handle, [-15.0, -30.0]) {
+
+        // The textbox clears the screen, so it has to come first.
+        self.draw.draw_textbox("filter", handle, [0.0, 19.0]);
+        self.draw.draw_label("Browse Sessions", handle, [0.0, 30.0]);
+        self.draw.draw_label("Filter:", handle, [0.0, 24.0]);
+        let sort = match self.browser.sort {
+            SortKey::Name => "Sort: name",
+            SortKey::Players => "Sort: players",
+        };
+        if self.draw.draw_button(sort, handle, [-15.0, 11.0]) {
+            self.browser.toggle_sort();
+        }
+        let full = if self.browser.hide_full { "Show full" } else { "Hide full" };
+        if self.draw.draw_button(full, handle, [0.0, 11.0]) {
+            self.browser.hide_full = !self.browser.hide_full;
+        }
+        if self.draw.draw_button("Refresh", handle, [15.0, 11.0]) {
+            self.refresh_sessions = true;
+        }
+
+        let filter = self.draw.text("filter");
+        let rows = self
+            .browser
+            .visible(&filter)
+            .into_iter()
+            .take(MAX_LISTED_SESSIONS)
+            .map(|session| (describe(session), session.name()))
+            .collect::<Vec<_>>();
+        if rows.is_empty() {
+            self.draw.draw_label("No sessions found", handle, [0.0, 3.0]);
+        }
+        for (i, (text, id)) in rows.into_iter().enumerate() {
+            if self.draw.draw_button(&text, handle, [0.0, 3.0 - i as f32 * 7.0]) {
+                self.draw.set_text("id", &id);
+                self.state = GameState::JoinMenu;
+            }
+        }
+        if self.draw.draw_button("Back to Main Menu", handle, [-15.0, -38.0]) {
+            self.state = GameState::MainMenu;
+        }
+    }
+
+    async fn list_sessions(&mut self) -> Result<(), ClientError> {
+        let stream = self.stream.as_mut().unwrap();
+        write_frame(stream, &ServerRequest::ListSessions).await?;
+        let list: SessionList = read_reply(stream).await?;
+        self.browser.set_sessions(list.sessions);
+        Ok(())
+    }
+    async fn draw_new_game_menu(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread) {
+        let mut handle = clear_screen(handle, thread);
+        let handle = &mut handle;
+
+        // Textboxes clear the screen, so they are drawn before the labels.
+        self.draw.draw_textbox("id", handle, [0.0, 22.0]);
+        self.draw.draw_textbox("passwd", handle, [0.0, 10.0]);
+        self.draw.draw_textbox("player_limit", handle, [0.0, -2.0]);
+        self.draw.draw_textbox("map", handle, [0.0, -14.0]);
+        self.draw.draw_textbox("round_time", handle, [0.0, -26.0]);
+        self.draw.draw_label("Create Game", handle, [0.0, 35.0]);
+        self.draw.draw_label("Session ID:", handle, [0.0, 27.0]);
+        self.draw.draw_label("Session Password:", handle, [0.0, 15.0]);
+        self.draw.draw_label("Player Limit:", handle, [0.0, 3.0]);
+        self.draw.draw_label("Map:", handle, [0.0, -9.0]);
+        self.draw.draw_label("Round Time (minutes, 0 for none):", handle, [0.0, -21.0]);
+        let mode = format!("Mode: {}", self.create_mode.name());
+        if self.draw.draw_button(&mode, handle, [-15.0, -33.0]) {
+            self.create_mode = self.create_mode.next();
+        }
+        let visibility = match self.create_visibility {
+            Visibility::Public => "Public",
+            Visibility::Private => "Private",
+        };
+        if self.draw.draw_button(visibility, handle, [15.0, -33.0]) {
+            self.create_visibility = match self.create_visibility {
+                Visibility::Public => Visibility::Private,
+                Visibility::Private => Visibility::Public,
+            };
+        }
+        if self.draw.draw_button("Back to Main Menu", handle, [-15.0, -41.0]) {
             self.state = GameState::MainMenu;
         }
-        if self.draw.draw_button("Create Session", handle, [15.0, -30.0]) {
-            match self.create_game(stream).await {
-                ServerResponse::Ok(signal) => {
-                    self.state = GameState::InGame;
-                    self.server_error = None;
-                },
-                ServerResponse::InvalidRequest(reason) => {
-                    self.state = GameState::ErrorMessage;
-                    self.server_error = Some(reason);
-                    *stream = get_stream().await;
+        if self.draw.draw_button("Create Session", handle, [15.0, -41.0]) {
+            let options = match self.session_options() {
+                Ok(options) => options,
+                Err(reason) => {
+                    self.show_error(reason.to_string());
+                    return;
                 }
+            };
+            if !self.ensure_connected().await {
+                return;
+            }
+            if let Err(err) = self.create_game(options).await {
+                self.fail(err);
             }
         }
     }
-    async fn create_game(&mut self, stream: &mut TcpStream) -> ServerResponse {
-        let id = String::from_utf8(self.draw.buffers.get("id").unwrap().1.to_vec()).unwrap();
-        let passwd = String::from_utf8(self.draw.buffers.get("passwd").unwrap().1.to_vec()).unwrap();
-        let request = ServerRequest::NewSession(NewSessionRequest::new(&id, &passwd));
-        stream.write(&request.to_bytes().unwrap()).await.unwrap();
-        stream.flush().await.unwrap();
-        let mut buffer = [0;1024];
-        stream.read(&mut buffer).await.unwrap();
-        ServerResponse::from_bytes((&buffer, 0)).unwrap().1
+
+    fn session_options(&self) -> Result<SessionOptions, Reason> {
+        let player_limit = self
+            .draw
+            .text("player_limit")
+            .trim()
+            .parse()
+            .map_err(|_| Reason::InvalidPlayerLimit)?;
+        let round_time = self
+            .draw
+            .text("round_time")
+            .trim()
+            .parse::<u16>()
+            .ok()
+            .and_then(|minutes| minutes.checked_mul(60))
+            .ok_or(Reason::InvalidRoundTime)?;
+        let options = SessionOptions::new(
+            player_limit,
+            self.draw.text("map").trim(),
+            self.create_mode,
+            self.create_visibility,
+            round_time,
+        );
+        options.validate()?;
+        Ok(options)
     }
 
-    async fn draw_join_game_menu(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread, stream: &mut TcpStream) {
+    async fn create_game(&mut self, options: SessionOptions) -> Result<(), ClientError> {
+        let (id, passwd) = self.credentials()?;
+        let nickname = self.nickname()?;
+        let request = if self.capabilities & CAP_CHALLENGE_AUTH != 0 {
+            let request = VerifiedSessionRequest::new(&id, &passwd, &nickname, options)?;
+            ServerRequest::NewVerifiedSession(request)
+        } else {
+            self.allow_plaintext(&passwd)?;
+            ServerRequest::NewSession(NewSessionRequest::new(&id, &passwd, &nickname, options))
+        };
+        let stream = self.stream.as_mut().unwrap();
+        write_frame(stream, &request).await?;
+        match read_reply(stream).await? {
+            ServerResponse::Ok(ticket, _) => self.start_game(ticket).await,
+            ServerResponse::InvalidRequest(reason) => Err(reason.into()),
+        }
+    }
+
+    async fn draw_join_game_menu(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread) {
         let mut handle = clear_screen(handle, thread);
         let handle = &mut handle;
 
@@ -123,17 +314,9 @@ impl GameManager {
         if self.draw.draw_button("Back to Main Menu", handle, [-15.0, -30.0]) {
             self.state = GameState::MainMenu;
         }
-        if self.draw.draw_button("Join Session", handle, [15.0, -30.0]) {
-            match self.join_game(stream).await {
-                JoinResponse::Ok => {
-                    self.state = GameState::InGame;
-                    self.server_error = None;
-                },
-                JoinResponse::Err(reason) => {
-                    self.state = GameState::ErrorMessage;
-                    self.server_error = reason.into();
-                    *stream = get_stream().await;
-                }
+        if self.draw.draw_button("Join Session", handle, [15.0, -30.0]) && self.ensure_connected().await {
+            if let Err(err) = self.join_game().await {
+                self.fail(err);
             }
         }
     }
@@ -141,64 +324,371 @@ impl GameManager {
     fn draw_server_error(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread) {
         let mut handle = clear_screen(handle, thread);
         let handle = &mut handle;
-        self.draw.draw_label(&self.server_error.as_ref().unwrap().to_string(), handle, [0.0, 30.0]);
+        self.draw.draw_label(self.error_message.as_deref().unwrap_or_default(), handle, [0.0, 30.0]);
         if self.draw.draw_button("Back", handle, [0.0, 0.0]) {
             self.state = GameState::MainMenu;
         }
     }
 
-    async fn join_game(&mut self, stream: &mut TcpStream) -> JoinResponse {
-        let id = String::from_utf8(self.draw.buffers.get("id").unwrap().1.to_vec()).unwrap();
-        let passwd = String::from_utf8(self.draw.buffers.get("passwd").unwrap().1.to_vec()).unwrap();
-        let request = ServerRequest::JoinSession(JoinSessionRequest::new(&id, &passwd));
-        stream.write(&request.to_bytes().unwrap()).await.unwrap();
-        let mut buffer = [0;1024];
-        stream.read(&mut buffer).await.unwrap();
-        JoinResponse::from_bytes((&buffer, 0)).unwrap().1
+    async fn join_game(&mut self) -> Result<(), ClientError> {
+        let (id, passwd) = self.credentials()?;
+        let nickname = self.nickname()?;
+        if self.capabilities & CAP_CHALLENGE_AUTH != 0 {
+            let stream = self.stream.as_mut().unwrap();
+            let ticket = auth::join(stream, &id, &passwd, &nickname).await?;
+            return self.start_game(ticket).await;
+        }
+        self.allow_plaintext(&passwd)?;
+        let stream = self.stream.as_mut().unwrap();
+        let request = ServerRequest::JoinSession(JoinSessionRequest::new(&id, &passwd, &nickname));
+        write_frame(stream, &request).await?;
+        match read_reply(stream).await? {
+            JoinResponse::Ok(ticket) => self.start_game(ticket).await,
+            JoinResponse::Err(reason) => Err(reason.into()),
+        }
+    }
+
+    fn allow_plaintext(&self, passwd: &str) -> Result<(), ClientError> {
+        if self.config.require_challenge_auth && !passwd.is_empty() {
+            return Err(ClientError::PlaintextPassword);
+        }
+        Ok(())
+    }
+
+    fn credentials(&self) -> Result<(String, String), Reason> {
+        let id = self.draw.text("id");
+        let passwd = self.draw.text("passwd");
+        validate_password(&passwd)?;
+        Ok((id, passwd))
+    }
+
+    fn nickname(&self) -> Result<String, Reason> {
+        let nickname = self.draw.text("nickname").trim().to_string();
+        validate_nickname(&nickname)?;
+        Ok(nickname)
+    }
+
+    async fn start_game(&mut self, ticket: SessionTicket) -> Result<(), ClientError> {
+        let mut stream = self.stream.take().unwrap();
+        let session = Session {
+            config: self.config.clone(),
+            capabilities: self.capabilities,
+            ticket,
+        };
+        let datagrams = session.open_datagrams(&mut stream).await?;
+        let conditions = self.conditions.clone();
+        self.network = Some(NetworkTask::spawn(stream, datagrams, session, conditions));
+        if let Some(path) = &self.config.record {
+            match DemoRecorder::create(path) {
+                Ok(recorder) => self.recorder = Some(recorder),
+                Err(err) => println!("Not recording to {path}: {err}"),
+            }
+        }
+        self.player_id = Some(ticket.player_id);
+        self.state = GameState::InGame;
+        self.error_message = None;
+        Ok(())
     }
 
     async fn do_game_logic(
         &mut self,
         handle: &mut RaylibHandle,
         thread: &RaylibThread,
-        stream: &mut TcpStream,
-    ) {
-        let new_state = network::connect(self, stream, handle).await;
-        self.player.set_state(new_state.clone());
+    ) -> Result<(), ClientError> {
+        if handle.is_key_pressed(KeyboardKey::KEY_F3) {
+            self.show_net_stats = !self.show_net_stats;
+        }
+        // Escape closes the chat box or the console before it opens the menu.
+        let was_typing = self.chat_open || self.console.open;
+        if self.console.open {
+            self.update_console(handle);
+        } else if self.chat_open {
+            self.update_chat_input(handle);
+        } else if !self.menu_open && handle.is_key_pressed(KeyboardKey::KEY_GRAVE) {
+            self.set_console_open(true);
+            while handle.get_char_pressed().is_some() {}
+        } else if !self.menu_open && handle.is_key_pressed(KeyboardKey::KEY_T) {
+            self.set_chat_open(true);
+            // The key that opened the chat must not end up in the message.
+            while handle.get_char_pressed().is_some() {}
+        }
+        if !was_typing && handle.is_key_pressed(KeyboardKey::KEY_ESCAPE) {
+            self.set_menu_open(handle, !self.menu_open);
+        }
+        let network = self.network.as_mut().unwrap();
+        if self.reconnecting.is_none() && !self.menu_open {
+            network.send(self.player.update(handle));
+        }
+        let mut new_state = None;
+        for event in network.poll() {
+            match event {
+                NetworkEvent::Snapshot(snapshot, received) => {
+                    if let Some(recorder) = self.recorder.as_mut() {
+                        if let Err(err) = recorder.record(&snapshot, received) {
+                            println!("Stopped recording: {err}");
+                            self.recorder = None;
+                        }
+                    }
+                    self.interpolation.push(received, Snapshot::from_signal(&snapshot));
+                    new_state = Some(snapshot);
+                }
+                NetworkEvent::Chat(line) => {
+                    self.chat.push(line, Instant::now());
+                }
+                NetworkEvent::Reconnecting(attempt) => {
+                    self.reconnecting = Some(attempt);
+                }
+                NetworkEvent::Resumed => {
+                    self.reconnecting = None;
+                    self.player.reset_prediction();
+                    self.interpolation.clear();
+                }
+                NetworkEvent::Disconnected(err) => return Err(err),
+            }
+        }
+        if let Some(new_state) = new_state {
+            self.apply_state(handle, thread, new_state)?;
+        }
+        self.interpolate_remote(Instant::now());
+        Ok(())
+    }
+
+    fn start_playback(&mut self, path: &str) -> Result<(), ClientError> {
+        self.playback = Some(DemoPlayer::new(Demo::load(path)?));
+        self.last_frame = Instant::now();
+        self.state = GameState::Playback;
+        Ok(())
+    }
+
+    // Snapshots come from the demo instead of the network. The camera follows
+    // the recorded player, so there is nothing to send.
+    fn do_playback(
+        &mut self,
+        handle: &mut RaylibHandle,
+        thread: &RaylibThread,
+    ) -> Result<(), ClientError> {
+        if handle.is_key_pressed(KeyboardKey::KEY_ESCAPE) {
+            self.set_menu_open(handle, !self.menu_open);
+        }
+        let now = Instant::now();
+        let elapsed = now - self.last_frame;
+        self.last_frame = now;
+        let playback = self.playback.as_mut().unwrap();
+        if self.menu_open {
+            return Ok(());
+        }
+        if handle.is_key_pressed(KeyboardKey::KEY_SPACE) {
+            playback.paused = !playback.paused;
+        }
+        if handle.is_key_pressed(KeyboardKey::KEY_UP) {
+            playback.faster();
+        }
+        if handle.is_key_pressed(KeyboardKey::KEY_DOWN) {
+            playback.slower();
+        }
+        let seek = if handle.is_key_pressed(KeyboardKey::KEY_RIGHT) {
+            Some(playback.position() + SEEK_STEP)
+        } else if handle.is_key_pressed(KeyboardKey::KEY_LEFT) {
+            Some(playback.position().saturating_sub(SEEK_STEP))
+        } else {
+            None
+        };
+        let mut new_state = None;
+        if let Some(to) = seek {
+            self.interpolation.clear();
+            self.player.reset_prediction();
+            new_state = playback.seek(to);
+            if let Some(state) = &new_state {
+                self.interpolation.push(now, Snapshot::from_signal(state));
+            }
+        }
+        for (snapshot, behind) in playback.advance(elapsed) {
+            self.interpolation.push(now - behind, Snapshot::from_signal(&snapshot));
+            new_state = Some(snapshot);
+        }
+        let paused = playback.paused;
+        if let Some(new_state) = new_state {
+            self.apply_state(handle, thread, new_state)?;
+        }
+        if !paused {
+            self.interpolate_remote(now);
+        }
+        Ok(())
+    }
+
+    fn update_chat_input(&mut self, handle: &mut RaylibHandle) {
+        while let Some(c) = handle.get_char_pressed() {
+            self.chat_input.push(c);
+        }
+        if handle.is_key_pressed(KeyboardKey::KEY_BACKSPACE) {
+            self.chat_input.text.pop();
+        }
+        if handle.is_key_pressed(KeyboardKey::KEY_TAB) {
+            self.chat_input.toggle_channel();
+        }
+        let wheel = handle.get_mouse_wheel_move();
+        if wheel != 0.0 {
+            self.chat.scroll(wheel.signum() as i32);
+        }
+        if handle.is_key_pressed(KeyboardKey::KEY_ENTER) {
+            if let (Some(message), Some(network)) = (self.chat_input.take(), self.network.as_ref()) {
+                network.chat(message);
+            }
+            self.set_chat_open(false);
+        } else if handle.is_key_pressed(KeyboardKey::KEY_ESCAPE) {
+            self.chat_input.text.clear();
+            self.set_chat_open(false);
+        }
+    }
+
+    fn update_console(&mut self, handle: &mut RaylibHandle) {
+        while let Some(c) = handle.get_char_pressed() {
+            self.console.push(c);
+        }
+        if handle.is_key_pressed(KeyboardKey::KEY_BACKSPACE) {
+            self.console.input.pop();
+        }
+        if handle.is_key_pressed(KeyboardKey::KEY_ENTER) {
+            self.console.submit(&self.conditions);
+        } else if handle.is_key_pressed(KeyboardKey::KEY_ESCAPE)
+            || handle.is_key_pressed(KeyboardKey::KEY_GRAVE)
+        {
+            self.console.input.clear();
+            self.set_console_open(false);
+        }
+    }
+
+    fn set_console_open(&mut self, open: bool) {
+        self.console.open = open;
+        self.player.movement_enabled = !open;
+    }
+
+    fn set_chat_open(&mut self, open: bool) {
+        self.chat_open = open;
+        self.player.movement_enabled = !open;
+        self.chat.reset_scroll();
+    }
+
+    fn set_menu_open(&mut self, handle: &mut RaylibHandle, open: bool) {
+        self.menu_open = open;
+        if open {
+            handle.enable_cursor();
+        } else {
+            handle.disable_cursor();
+        }
+    }
+
+    async fn leave_session(&mut self, handle: &mut RaylibHandle) {
+        if let Some(network) = self.network.take() {
+            network.leave().await;
+        }
+        self.leave_game(handle);
+    }
+
+    // Called once the window is closing so the server frees the slot now.
+    pub async fn shutdown(&mut self) {
+        if let Some(network) = self.network.take() {
+            network.leave().await;
+        }
+        self.stop_recording();
+    }
+
+    fn stop_recording(&mut self) {
+        if let Some(Err(err)) = self.recorder.take().map(DemoRecorder::finish) {
+            println!("Could not finish the demo: {err}");
+        }
+    }
+
+    fn leave_game(&mut self, handle: &mut RaylibHandle) {
+        self.network = None;
+        self.stop_recording();
+        self.playback = None;
+        self.reconnecting = None;
+        self.menu_open = false;
+        self.player_id = None;
+        self.players.clear();
+        self.roster.clear();
+        self.chat.clear();
+        self.set_chat_open(false);
+        self.set_console_open(false);
+        self.interpolation.clear();
+        self.state = GameState::MainMenu;
+        self.once_game = false;
+        handle.enable_cursor();
+    }
+
+    async fn connect(&mut self) -> bool {
+        let mut stream = match get_stream(&self.config).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                let (host, port) = self.config.endpoint();
+                self.show_error(format!("Could not connect to {host} port {port}: {err}"));
+                return false;
+            }
+        };
+        match handshake(&mut stream).await {
+            Ok(capabilities) => {
+                self.stream = Some(stream);
+                self.capabilities = capabilities;
+                true
+            }
+            Err(err) => {
+                self.show_error(err.to_string());
+                false
+            }
+        }
+    }
+
+    fn show_error(&mut self, message: String) {
+        self.state = GameState::ErrorMessage;
+        self.error_message = Some(message);
+    }
+
+    // The stream is in an unknown state after a failed exchange, so the next
+    // attempt starts from a fresh connection.
+    fn fail(&mut self, err: ClientError) {
+        println!("{err}");
+        self.stream = None;
+        self.show_error(err.to_string());
+    }
+
+    async fn ensure_connected(&mut self) -> bool {
+        self.stream.is_some() || self.connect().await
+    }
+
+    fn apply_state(
+        &mut self,
+        handle: &mut RaylibHandle,
+        thread: &RaylibThread,
+        new_state: ResponseSignal,
+    ) -> Result<(), ClientError> {
+        self.roster = new_state
+            .players
+            .iter()
+            .map(|player| (player.id, player.clone()))
+            .collect();
+        self.player.reconcile(new_state.clone());
         for x in new_state.objects.iter() {
-            if let Some(object) = self
-                .objects
-                .get_mut(&String::from_utf8(x.id.clone()).unwrap())
-            {
-                object.update(x);
-            } else {
-                let object = Object::new(
-                    handle,
-                    thread,
-                    String::from_utf8(x.id.clone()).unwrap(),
-                    x.position,
-                    x.rotation,
-                );
-                self.objects
-                    .insert(String::from_utf8(x.id.clone()).unwrap(), object);
+            let id = String::from_utf8(x.id.clone())?;
+            if !self.objects.contains_key(&id) {
+                let object = Object::new(handle, thread, id.clone(), x.position, x.rotation)?;
+                self.objects.insert(id, object);
             }
         }
-        self.players = new_state.players;
-        /*for player in self.players.iter() {
-            let mut draw_handle = handle.begin_drawing(thread);
-            let mut draw_handle = draw_handle.begin_mode3D(self.player.camera);
-            println!("drawing players");
-            draw_handle.draw_model(
-                &self.player.object.model,
-                Vector3::new(
-                    player.translation[0],
-                    player.translation[1],
-                    player.translation[2],
-                ),
-                1.0,
-                Color::WHITE,
-            );
-        }*/
+        Ok(())
+    }
+
+    fn interpolate_remote(&mut self, now: Instant) {
+        let Some(snapshot) = self.interpolation.sample(now) else {
+            return;
+        };
+        for (id, transform) in snapshot.objects.iter() {
+            if let Some(object) = self.objects.get_mut(id) {
+                object.position = transform.position;
+                object.rotation = transform.rotation;
+            }
+        }
+        self.players = snapshot.players;
     }
     pub fn new(
         sky_shader: Shader,
@@ -206,31 +696,167 @@ impl GameManager {
         handle: &mut RaylibHandle,
         thread: &RaylibThread,
         model: Model,
-    ) -> Self {
-        Self {
+        config: Config,
+    ) -> Result<Self, ClientError> {
+        let mut manager = Self {
             sky_shader,
-            players: Vec::new(),
+            players: HashMap::new(),
+            roster: HashMap::new(),
+            player_id: None,
             objects: HashMap::new(),
             player: Player::new(
                 camera,
                 1.0,
-                Object::new(handle, thread, "DCPlayer".into(), [0.0; 3], [0.0; 4]),
+                Object::new(handle, thread, "DCPlayer".into(), [0.0; 3], [0.0; 4])?,
                 Vector3::zero(),
+                config.movement,
             ),
             state: GameState::MainMenu,
             once_game: false,
             draw: Draw::new(handle),
-            server_error: None
+            error_message: None,
+            stream: None,
+            capabilities: 0,
+            network: None,
+            reconnecting: None,
+            show_net_stats: false,
+            menu_open: false,
+            chat: ChatHistory::default(),
+            chat_input: ChatInput::default(),
+            chat_open: false,
+            console: Console::default(),
+            conditions: Arc::new(Mutex::new(config.net)),
+            create_mode: GameMode::FreeForAll,
+            create_visibility: Visibility::Public,
+            browser: SessionBrowser::default(),
+            refresh_sessions: false,
+            interpolation: SnapshotBuffer::new(config.interpolation_delay),
+            recorder: None,
+            playback: None,
+            last_frame: Instant::now(),
+            config,
+        };
+        let nickname = manager.config.nickname.clone();
+        manager.draw.set_text("nickname", &nickname);
+        if let Some(path) = manager.config.play.clone() {
+            if let Err(err) = manager.start_playback(&path) {
+                manager.fail(err);
+            }
         }
+        Ok(manager)
     }
 
-    fn draw_game(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread) {
+    // Returns true when the player chose to leave the session.
+    fn draw_game(&mut self, handle: &mut RaylibHandle, thread: &RaylibThread) -> bool {
         let mut draw_handle = handle.begin_drawing(thread);
         draw_handle.clear_background(Color::WHITE);
 
         self.draw_sky(&mut draw_handle);
         self.draw_objects(&mut draw_handle);
+        self.draw_nicknames(&mut draw_handle);
         self.draw_lights(&mut draw_handle);
+        if let Some(attempt) = self.reconnecting {
+            let text = format!("Reconnecting (attempt {attempt})...");
+            draw_handle.draw_text(&text, 20, 20, 30, Color::RED);
+        }
+        if self.show_net_stats {
+            self.draw_net_stats(&mut draw_handle);
+        }
+        self.draw_chat(&mut draw_handle);
+        self.draw_playback(&mut draw_handle);
+        self.draw_console(&mut draw_handle);
+        if !self.menu_open {
+            return false;
+        }
+        self.draw.draw_label("Paused", &mut draw_handle, [0.0, 25.0]);
+        if self.draw.draw_button("Resume", &mut draw_handle, [0.0, 5.0]) {
+            self.set_menu_open(&mut draw_handle, false);
+        }
+        let leave = if self.playback.is_some() {
+            "Stop playback"
+        } else {
+            "Leave session"
+        };
+        self.draw.draw_button(leave, &mut draw_handle, [0.0, -10.0])
+    }
+
+    fn draw_playback(&self, handle: &mut RaylibDrawHandle) {
+        let Some(playback) = self.playback.as_ref() else {
+            return;
+        };
+        let state = if playback.paused {
+            "paused"
+        } else if playback.finished() {
+            "finished"
+        } else {
+            "playing"
+        };
+        let text = format!(
+            "Demo {} / {} x{} {state}",
+            format_time(playback.position()),
+            format_time(playback.duration()),
+            playback.speed
+        );
+        let y = handle.get_screen_height() - 50;
+        handle.draw_text(&text, 20, y, 20, Color::BLACK);
+        let help = "Space pause, Left/Right seek, Up/Down speed";
+        handle.draw_text(help, 20, y + 25, 16, Color::DARKGRAY);
+    }
+
+    fn draw_chat(&self, handle: &mut RaylibDrawHandle) {
+        let bottom = handle.get_screen_height() - 60;
+        if self.chat_open {
+            let text = format!("[{}] > {}_", self.chat_input.channel.name(), self.chat_input.text);
+            handle.draw_text(&text, 20, bottom + 25, 20, Color::BLACK);
+        }
+        let lines = self.chat.visible(Instant::now(), self.chat_open);
+        for (i, (line, alpha)) in lines.iter().rev().enumerate() {
+            let color = match line.channel {
+                ChatChannel::All => Color::BLACK,
+                ChatChannel::Team => Color::DARKBLUE,
+            };
+            let text = format!("[{}] {}: {}", line.channel.name(), line.sender, line.text);
+            handle.draw_text(&text, 20, bottom - i as i32 * 22, 20, color.fade(*alpha));
+        }
+    }
+
+    fn draw_console(&self, handle: &mut RaylibDrawHandle) {
+        if !self.console.open {
+            return;
+        }
+        let lines = self.console.output().collect::<Vec<_>>();
+        let width = handle.get_screen_width();
+        let height = (lines.len() as i32 + 1) * 22 + 10;
+        handle.draw_rectangle(0, 0, width, height, Color::BLACK.fade(0.7));
+        for (i, line) in lines.iter().enumerate() {
+            handle.draw_text(line, 10, 5 + i as i32 * 22, 20, Color::LIGHTGRAY);
+        }
+        let prompt = format!("] {}_", self.console.input);
+        handle.draw_text(&prompt, 10, 5 + lines.len() as i32 * 22, 20, Color::WHITE);
+    }
+
+    fn draw_net_stats(&self, handle: &mut RaylibDrawHandle) {
+        let Some(network) = self.network.as_ref() else {
+            return;
+        };
+        let stats = network.stats();
+        let inputs = network.inputs();
+        let millis = |duration: Option<Duration>| match duration {
+            Some(duration) => format!("{:.1} ms", duration.as_secs_f32() * 1000.0),
+            None => "-".into(),
+        };
+        let lines = [
+            format!("RTT: {}", millis(stats.rtt)),
+            format!("Last ping: {}", millis(stats.last_rtt)),
+            format!("Jitter: {}", millis(Some(stats.jitter))),
+            format!("Input RTT: {}", millis(inputs.last_round_trip())),
+            format!("Inputs in flight: {}", inputs.in_flight()),
+            format!("Last packet: {}", millis(Some(stats.since_last_packet))),
+        ];
+        let x = handle.get_screen_width() - 240;
+        for (i, line) in lines.iter().enumerate() {
+            handle.draw_text(line, x, 20 + i as i32 * 22, 20, Color::DARKGREEN);
+        }
     }
 
     fn draw_sky(&mut self, handle: &mut RaylibDrawHandle) {
@@ -252,16 +878,28 @@ impl GameManager {
             draw.draw_model(&object.model, object.position, 1.0, Color::WHITE);
         }
 
-        for player in self.players.iter() {
-            draw.draw_model(
-                &self.player.object.model,
-                Vector3::new(
-                    player.translation[0],
-                    player.translation[1],
-                    player.translation[2],
-                ),
-                1.0,
-                Color::WHITE,
+        for (id, player) in self.players.iter() {
+            if Some(*id) != self.player_id {
+                draw.draw_model(&self.player.object.model, player.position, 1.0, Color::WHITE);
+            }
+        }
+    }
+
+    fn draw_nicknames(&self, handle: &mut RaylibDrawHandle) {
+        for (id, player) in self.players.iter() {
+            let Some(remote) = self.roster.get(id).filter(|_| Some(*id) != self.player_id) else {
+                continue;
+            };
+            let above = player.position + Vector3::up() * 1.5;
+            let screen = handle.get_world_to_screen(above, self.player.camera);
+            let nickname = remote.nickname();
+            let width = measure_text(&nickname, 20);
+            handle.draw_text(
+                &nickname,
+                screen.x as i32 - width / 2,
+                screen.y as i32,
+                20,
+                team_color(remote.team),
             );
         }
     }
@@ -302,3 +940,16 @@ fn clear_screen<'a>(handle: &'a mut RaylibHandle, thread: &'a RaylibThread) -> R
     handle.clear_background(Color::WHITE);
     handle
 }
+
+fn format_time(time: Duration) -> String {
+    let seconds = time.as_secs();
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+fn team_color(team: u8) -> Color {
+    match team {
+        1 => Color::RED,
+        2 => Color::BLUE,
+        _ => Color::BLACK,
+    }
+}
@@ -48,6 +48,21 @@ impl Draw {
         }
         rtn
     }
+    pub fn set_text(&mut self, id: &str, text: &str) {
+        let mut buffer = [0; 1024];
+        let len = text.len().min(buffer.len() - 1);
+        buffer[..len].copy_from_slice(&text.as_bytes()[..len]);
+        self.buffers.insert(id.into(), (false, buffer));
+    }
+    pub fn text(&self, id: &str) -> String {
+        match self.buffers.get(id) {
+            Some((_state, buffer)) => {
+                let len = buffer.iter().position(|x| *x == 0).unwrap_or(buffer.len());
+                String::from_utf8_lossy(&buffer[..len]).into_owned()
+            }
+            None => String::new(),
+        }
+    }
     pub fn draw_textbox(&mut self, id: &str, handle: &mut RaylibDrawHandle, position: [f32;2]) -> bool {
         let (height, width) = (self.textbox_height, self.textbox_width);
         let (x, y) = (self.screen_x, self.screen_y);
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use deku::ctx::Endian;
+use deku::prelude::*;
+
+use crate::codec::WIRE_ENDIAN;
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+pub const DEFAULT_SILENCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Pings older than this can no longer be matched to a pong.
+const MAX_OUTSTANDING_PINGS: usize = 16;
+
+// The server answers every ping with a pong carrying the same id.
+#[derive(DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct Ping {
+    pub id: u32,
+}
+
+#[derive(DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct Pong {
+    pub id: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LinkStats {
+    pub rtt: Option<Duration>,
+    pub jitter: Duration,
+    pub last_rtt: Option<Duration>,
+    pub since_last_packet: Duration,
+}
+
+// Smoothed round trip and jitter in the style of TCP's SRTT/RTTVAR, fed by
+// ping/pong pairs. Any frame from the server counts as a sign of life.
+pub struct LinkMonitor {
+    outstanding: VecDeque<(u32, Instant)>,
+    next_ping: u32,
+    rtt: Option<Duration>,
+    jitter: Duration,
+    last_rtt: Option<Duration>,
+    last_received: Instant,
+}
+
+impl Default for LinkMonitor {
+    fn default() -> Self {
+        Self {
+            outstanding: VecDeque::new(),
+            next_ping: 0,
+            rtt: None,
+            jitter: Duration::ZERO,
+            last_rtt: None,
+            last_received: Instant::now(),
+        }
+    }
+}
+
+impl LinkMonitor {
+    // A new connection starts out alive and with no pings in flight; the
+    // estimates carry over since the route to the server is likely the same.
+    pub fn reset(&mut self, now: Instant) {
+        self.outstanding.clear();
+        self.last_received = now;
+    }
+
+    pub fn ping(&mut self, now: Instant) -> Ping {
+        let id = self.next_ping;
+        self.next_ping = self.next_ping.wrapping_add(1);
+        self.outstanding.push_back((id, now));
+        if self.outstanding.len() > MAX_OUTSTANDING_PINGS {
+            self.outstanding.pop_front();
+        }
+        Ping { id }
+    }
+
+    pub fn pong(&mut self, pong: Pong, now: Instant) {
+        self.received(now);
+        let Some(index) = self.outstanding.iter().position(|(id, _)| *id == pong.id) else {
+            return;
+        };
+        let (_, sent) = self.outstanding[index];
+        self.outstanding.drain(..=index);
+        let sample = now.duration_since(sent);
+        self.last_rtt = Some(sample);
+        self.rtt = Some(match self.rtt {
+            None => {
+                self.jitter = sample / 2;
+                sample
+            }
+            Some(rtt) => {
+                let deviation = if rtt > sample { rtt - sample } else { sample - rtt };
+                self.jitter = self.jitter.mul_f32(0.75) + deviation.mul_f32(0.25);
+                rtt.mul_f32(0.875) + sample.mul_f32(0.125)
+            }
+        });
+    }
+
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    pub fn silent_for(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_received)
+    }
+
+    pub fn stats(&self, now: Instant) -> LinkStats {
+        LinkStats {
+            rtt: self.rtt,
+            jitter: self.jitter,
+            last_rtt: self.last_rtt,
+            since_last_packet: self.silent_for(now),
+        }
+    }
+}
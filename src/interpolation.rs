@@ -0,0 +1,276 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use raylib::math::{Quaternion, Vector3};
+
+use crate::network::ResponseSignal;
+
+pub const DEFAULT_INTERPOLATION_DELAY: Duration = Duration::from_millis(100);
+const MAX_EXTRAPOLATION: Duration = Duration::from_millis(250);
+const MAX_SNAPSHOTS: usize = 64;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Transform {
+    pub position: Vector3,
+    pub rotation: Quaternion,
+}
+
+impl Transform {
+    pub fn new(position: [f32; 3], rotation: [f32; 4]) -> Self {
+        Self {
+            position: Vector3::new(position[0], position[1], position[2]),
+            rotation: Quaternion::new(rotation[0], rotation[1], rotation[2], rotation[3]),
+        }
+    }
+
+    fn interpolate(&self, to: &Transform, amount: f32) -> Transform {
+        Transform {
+            position: self.position + (to.position - self.position) * amount,
+            rotation: slerp(self.rotation, to.rotation, amount),
+        }
+    }
+
+    // Past the newest snapshot only the position keeps moving; guessing
+    // rotations ahead of time looks worse than holding the last one.
+    fn extrapolate(&self, to: &Transform, amount: f32) -> Transform {
+        Transform {
+            position: self.position + (to.position - self.position) * amount,
+            rotation: to.rotation,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    pub players: HashMap<u32, Transform>,
+    pub objects: HashMap<String, Transform>,
+}
+
+impl Snapshot {
+    pub fn from_signal(signal: &ResponseSignal) -> Self {
+        Self {
+            players: signal
+                .players
+                .iter()
+                .map(|player| (player.id, Transform::new(player.position, player.rotation)))
+                .collect(),
+            objects: signal
+                .objects
+                .iter()
+                .map(|object| {
+                    (
+                        String::from_utf8_lossy(&object.id).into_owned(),
+                        Transform::new(object.position, object.rotation),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn blend(&self, to: &Snapshot, amount: f32, mix: fn(&Transform, &Transform, f32) -> Transform) -> Snapshot {
+        let players = to
+            .players
+            .iter()
+            .map(|(id, player)| {
+                let player = match self.players.get(id) {
+                    Some(from) => mix(from, player, amount),
+                    None => *player,
+                };
+                (*id, player)
+            })
+            .collect();
+        let objects = to
+            .objects
+            .iter()
+            .map(|(id, object)| {
+                let object = match self.objects.get(id) {
+                    Some(from) => mix(from, object, amount),
+                    None => *object,
+                };
+                (id.clone(), object)
+            })
+            .collect();
+        Snapshot { players, objects }
+    }
+}
+
+// Remote state is rendered `delay` in the past so there are usually two
+// received snapshots around the render time to blend between.
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<(Instant, Snapshot)>,
+    delay: Duration,
+}
+
+impl SnapshotBuffer {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            snapshots: VecDeque::new(),
+            delay,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    pub fn push(&mut self, received: Instant, snapshot: Snapshot) {
+        self.snapshots.push_back((received, snapshot));
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn sample(&mut self, now: Instant) -> Option<Snapshot> {
+        let render_time = now.checked_sub(self.delay).unwrap_or(now);
+        while self.snapshots.len() > 2 && self.snapshots[1].0 <= render_time {
+            self.snapshots.pop_front();
+        }
+        let (first_time, first) = self.snapshots.front()?;
+        if render_time <= *first_time {
+            return Some(first.clone());
+        }
+        let Some((second_time, second)) = self.snapshots.get(1) else {
+            return Some(first.clone());
+        };
+        let span = second_time.duration_since(*first_time).as_secs_f32();
+        if span <= 0.0 {
+            return Some(second.clone());
+        }
+        let elapsed = render_time.duration_since(*first_time);
+        if render_time <= *second_time {
+            let amount = elapsed.as_secs_f32() / span;
+            return Some(first.blend(second, amount, Transform::interpolate));
+        }
+        // The buffer ran dry: keep the motion going for a short while, then
+        // hold where that got to until the next snapshot arrives.
+        let elapsed = elapsed.min(second_time.duration_since(*first_time) + MAX_EXTRAPOLATION);
+        let amount = elapsed.as_secs_f32() / span;
+        Some(first.blend(second, amount, Transform::extrapolate))
+    }
+}
+
+fn slerp(from: Quaternion, to: Quaternion, amount: f32) -> Quaternion {
+    let mut to = to;
+    let mut cos = from.x * to.x + from.y * to.y + from.z * to.z + from.w * to.w;
+    if cos < 0.0 {
+        cos = -cos;
+        to = Quaternion::new(-to.x, -to.y, -to.z, -to.w);
+    }
+    let (from_weight, to_weight) = if cos > 0.9995 {
+        (1.0 - amount, amount)
+    } else {
+        let angle = cos.acos();
+        let sin = angle.sin();
+        (
+            ((1.0 - amount) * angle).sin() / sin,
+            (amount * angle).sin() / sin,
+        )
+    };
+    let result = Quaternion::new(
+        from.x * from_weight + to.x * to_weight,
+        from.y * from_weight + to.y * to_weight,
+        from.z * from_weight + to.z * to_weight,
+        from.w * from_weight + to.w * to_weight,
+    );
+    let length = (result.x * result.x + result.y * result.y + result.z * result.z + result.w * result.w).sqrt();
+    if length > 0.0 {
+        Quaternion::new(result.x / length, result.y / length, result.z / length, result.w / length)
+    } else {
+        to
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f32 = 1e-4;
+
+    fn snapshot(x: f32, rotation: Quaternion) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+        let position = Vector3::new(x, 0.0, 0.0);
+        snapshot.players.insert(1, Transform { position, rotation });
+        snapshot
+    }
+
+    // A buffer with player 1 at x = 10 * i received `i` times 100 ms in.
+    fn buffer(count: u32) -> (SnapshotBuffer, Instant) {
+        let mut buffer = SnapshotBuffer::new(Duration::from_millis(100));
+        let start = Instant::now();
+        for i in 0..count {
+            let received = start + Duration::from_millis(100) * i;
+            buffer.push(received, snapshot(10.0 * i as f32, yaw(0.0)));
+        }
+        (buffer, start)
+    }
+
+    fn player_x(buffer: &mut SnapshotBuffer, now: Instant) -> f32 {
+        buffer.sample(now).unwrap().players[&1].position.x
+    }
+
+    // Rotation by `angle` radians about the y axis.
+    fn yaw(angle: f32) -> Quaternion {
+        Quaternion::new(0.0, (angle / 2.0).sin(), 0.0, (angle / 2.0).cos())
+    }
+
+    fn assert_same_rotation(a: Quaternion, b: Quaternion) {
+        let dot = a.x * b.x + a.y * b.y + a.z * b.z + a.w * b.w;
+        assert!((dot.abs() - 1.0).abs() < TOLERANCE, "{a:?} is not {b:?}");
+    }
+
+    #[test]
+    fn blends_the_snapshots_around_the_render_time() {
+        let (mut buffer, start) = buffer(3);
+        let delay = Duration::from_millis(100);
+        assert_eq!(player_x(&mut buffer, start + delay / 2), 0.0);
+        assert!((player_x(&mut buffer, start + delay * 2 + delay / 2) - 15.0).abs() < TOLERANCE);
+        assert!((player_x(&mut buffer, start + delay * 3) - 20.0).abs() < TOLERANCE);
+        assert_eq!(buffer.snapshots.len(), 2);
+    }
+
+    #[test]
+    fn extrapolates_a_dry_buffer_for_a_limited_time() {
+        let (mut buffer, start) = buffer(1);
+        buffer.push(start + Duration::from_millis(100), snapshot(10.0, yaw(1.0)));
+
+        // 50 ms past the newest snapshot the player is still on the move.
+        let now = start + Duration::from_millis(250);
+        assert!((player_x(&mut buffer, now) - 15.0).abs() < TOLERANCE);
+        // Then it stops 250 ms past it, holding the newest rotation.
+        let sampled = buffer.sample(start + Duration::from_secs(5)).unwrap();
+        assert!((sampled.players[&1].position.x - 35.0).abs() < TOLERANCE);
+        assert_same_rotation(sampled.players[&1].rotation, yaw(1.0));
+    }
+
+    #[test]
+    fn keeps_only_the_newest_snapshots() {
+        let (buffer, start) = buffer(MAX_SNAPSHOTS as u32 + 6);
+        assert_eq!(buffer.snapshots.len(), MAX_SNAPSHOTS);
+        assert_eq!(buffer.snapshots[0].0, start + Duration::from_millis(600));
+    }
+
+    #[test]
+    fn slerp_takes_the_shortest_path() {
+        let from = yaw(0.0);
+        let to = yaw(std::f32::consts::FRAC_PI_2);
+        let flipped = Quaternion::new(-to.x, -to.y, -to.z, -to.w);
+        for to in [to, flipped] {
+            assert_same_rotation(slerp(from, to, 0.5), yaw(std::f32::consts::FRAC_PI_4));
+            assert_same_rotation(slerp(from, to, 1.0), yaw(std::f32::consts::FRAC_PI_2));
+        }
+    }
+
+    #[test]
+    fn slerp_handles_nearly_parallel_rotations() {
+        let (from, to) = (yaw(0.5), yaw(0.5001));
+        for amount in [0.0, 0.25, 1.0] {
+            let result = slerp(from, to, amount);
+            let length = (result.x * result.x + result.y * result.y
+                + result.z * result.z
+                + result.w * result.w)
+                .sqrt();
+            assert!((length - 1.0).abs() < TOLERANCE);
+            assert_same_rotation(result, yaw(0.5 + 0.0001 * amount));
+        }
+    }
+}
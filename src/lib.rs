@@ -0,0 +1,24 @@
+pub mod auth;
+pub mod browser;
+pub mod chat;
+pub mod codec;
+pub mod config;
+pub mod connection;
+pub mod console;
+pub mod datagram;
+pub mod delta;
+pub mod demo;
+pub mod error;
+pub mod gui;
+pub mod heartbeat;
+pub mod interpolation;
+pub mod session;
+pub mod game;
+pub mod lights;
+pub mod mock_server;
+pub mod netsim;
+pub mod network;
+pub mod objects;
+pub mod player;
+pub mod quantize;
+pub mod reader;
@@ -1,19 +1,26 @@
-use game::GameManager;
-use network::get_stream;
+use aimclient::config::Config;
+use aimclient::error::ClientError;
+use aimclient::game::GameManager;
+use aimclient::mock_server::{self, MockServer};
+use raylib::prelude::{Model, RaylibHandle, RaylibThread, Shader};
 use raylib::{camera::Camera3D, math::Vector3, shaders::RaylibShader};
-use tokio::io::AsyncWriteExt;
-
-pub mod gui;
-pub mod session;
-pub mod game;
-pub mod lights;
-pub mod network;
-pub mod objects;
-pub mod player;
-pub mod reader;
 
 #[tokio::main]
 async fn main() {
+    if let Some((port, script)) = mock_server::from_args(std::env::args().skip(1)) {
+        let server = match MockServer::bind(("127.0.0.1", port), script).await {
+            Ok(server) => server,
+            Err(err) => {
+                println!("Could not start the mock server: {err}");
+                return;
+            }
+        };
+        println!("Mock server listening on port {port}");
+        if let Err(err) = server.run().await {
+            println!("Mock server stopped: {err}");
+        }
+        return;
+    }
     //delete_models();
     //build_models("static/models/scene.obj", "static/models/scene.mtl");
     let (mut handle, thread) = raylib::init()
@@ -25,30 +32,61 @@ async fn main() {
         .resizable()
         .build();
     handle.set_target_fps(60);
+    // Escape opens the in-game menu instead of closing the window.
+    handle.set_exit_key(None);
     handle.gui_enable();
 
+    let (sky_shader, mut light_shader, player_model) = match load_assets(&mut handle, &thread) {
+        Ok(assets) => assets,
+        Err(err) => {
+            println!("Could not start the client: {err}");
+            return;
+        }
+    };
+
+    let camera = Camera3D::perspective(Vector3::zero(), Vector3::zero(), Vector3::up(), 90.0);
+    let config = Config::load();
+    let mut manager = match GameManager::new(
+        sky_shader,
+        camera,
+        &mut handle,
+        &thread,
+        player_model,
+        config,
+    ) {
+        Ok(manager) => manager,
+        Err(err) => {
+            println!("Could not start the client: {err}");
+            return;
+        }
+    };
+
+    while !handle.window_should_close() {
+        manager.update(&mut handle, &thread).await;
+    }
+    manager.shutdown().await;
+}
+
+fn load_assets(
+    handle: &mut RaylibHandle,
+    thread: &RaylibThread,
+) -> Result<(Shader, Shader, Model), ClientError> {
+    let asset = |name: &str| {
+        let name = name.to_string();
+        move |reason| ClientError::Asset { name, reason }
+    };
     let sky_shader = handle
-        .load_shader(&thread, None, Some("static/shaders/shader.fs"))
-        .unwrap();
-    let mut light_shader = handle
+        .load_shader(thread, None, Some("static/shaders/shader.fs"))
+        .map_err(asset("static/shaders/shader.fs"))?;
+    let light_shader = handle
         .load_shader(
-            &thread,
+            thread,
             Some("static/shaders/lighting.vs"),
             Some("static/shaders/lightning.fs"),
         )
-        .unwrap();
-
+        .map_err(asset("static/shaders/lighting.vs"))?;
     let player_model = handle
-        .load_model(&thread, "static/models/ball.obj")
-        .unwrap();
-
-    let camera = Camera3D::perspective(Vector3::zero(), Vector3::zero(), Vector3::up(), 90.0);
-    let mut manager = GameManager::new(sky_shader, camera, &mut handle, &thread, player_model);
-
-    let mut stream = get_stream().await;
-
-    while !handle.window_should_close() {
-        manager.update(&mut handle, &thread, &mut stream).await;
-    }
-    stream.flush().await.unwrap();
+        .load_model(thread, "static/models/ball.obj")
+        .map_err(asset("static/models/ball.obj"))?;
+    Ok((sky_shader, light_shader, player_model))
 }
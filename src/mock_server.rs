@@ -0,0 +1,878 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use deku::prelude::*;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::interval;
+
+use crate::auth::{
+    check_proof, random_bytes, verifier, Challenge, ChallengeRequest, ChallengeResponse,
+    VerifiedSessionRequest, DIGEST_SIZE, NONCE_SIZE, SALT_SIZE,
+};
+use crate::chat::ChatMessage;
+use crate::codec::{
+    decode_raw, encode, read_frame, read_raw_frame, write_frame, FrameError, MessageKind, RawFrame,
+};
+use crate::config::DEFAULT_PORT;
+use crate::datagram::TOKEN_SIZE;
+use crate::heartbeat::{Ping, Pong};
+use crate::network::{
+    Hello, HelloResponse, PlayerSignal, Reason, ResponseSignal, ServerResponse, CAP_CHALLENGE_AUTH,
+    CAP_UDP, PROTOCOL_VERSION,
+};
+use crate::session::*;
+
+pub const DEFAULT_TICK: Duration = Duration::from_millis(50);
+
+// Ways the mock server misbehaves on purpose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    // Refuse every create, join and resume with this reason, or the
+    // handshake itself for `VersionMismatch`.
+    Reject(Reason),
+    // Send a header promising more bytes than follow, then hang up.
+    TruncatedFrame,
+    // Hang up as soon as a client enters a session.
+    Disconnect,
+    // Hang up after sending this many snapshots.
+    DisconnectAfter(u32),
+}
+
+impl Failure {
+    // `reject:<Reason>`, `truncate`, `disconnect` or `disconnect:<snapshots>`.
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.split_once(':') {
+            Some(("reject", reason)) => parse_reason(reason).map(Failure::Reject),
+            Some(("disconnect", count)) => count.parse().ok().map(Failure::DisconnectAfter),
+            None if value == "truncate" => Some(Failure::TruncatedFrame),
+            None if value == "disconnect" => Some(Failure::Disconnect),
+            _ => None,
+        }
+    }
+}
+
+// What the server sends once a client is in a session: `snapshots` are
+// cycled every `tick` with `last_input` filled in from the client.
+#[derive(Clone)]
+pub struct Script {
+    pub snapshots: Vec<ResponseSignal>,
+    pub failures: Vec<Failure>,
+    pub tick: Duration,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self {
+            snapshots: vec![ResponseSignal::default()],
+            failures: Vec::new(),
+            tick: DEFAULT_TICK,
+        }
+    }
+}
+
+impl Script {
+    fn rejection(&self) -> Option<Reason> {
+        self.failures.iter().find_map(|failure| match failure {
+            Failure::Reject(reason) => Some(*reason),
+            _ => None,
+        })
+    }
+
+    fn disconnect_after(&self) -> Option<u32> {
+        self.failures.iter().find_map(|failure| match failure {
+            Failure::DisconnectAfter(count) => Some(*count),
+            _ => None,
+        })
+    }
+}
+
+// `--mock-server [port]` switches the binary into serving on the loopback
+// interface instead of opening a window; `--fail <failure>` may be repeated
+// and `--tick <ms>` sets the snapshot rate.
+pub fn from_args(args: impl Iterator<Item = String>) -> Option<(u16, Script)> {
+    let args = args.collect::<Vec<_>>();
+    if !args.iter().any(|arg| arg == "--mock-server") {
+        return None;
+    }
+    let mut args = args.into_iter().peekable();
+    let mut port = DEFAULT_PORT;
+    let mut script = Script::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--mock-server" => {
+                if let Some(value) = args.next_if(|value| value.parse::<u16>().is_ok()) {
+                    port = value.parse().unwrap();
+                }
+            }
+            "--fail" => match args.next().as_deref().and_then(Failure::parse) {
+                Some(failure) => script.failures.push(failure),
+                None => println!("Ignoring unknown failure"),
+            },
+            "--tick" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(ms) if ms > 0 => script.tick = Duration::from_millis(ms),
+                _ => println!("Ignoring invalid tick"),
+            },
+            _ => println!("Ignoring argument {arg}"),
+        }
+    }
+    Some((port, script))
+}
+
+// A server speaking the real protocol for driving the client without the
+// actual game server. It keeps sessions in memory, supports create, join
+// (plain or challenge-response), resume, leave and listing, answers pings,
+// echoes chat back to the sender and binds UDP channels for snapshots.
+pub struct MockServer {
+    listener: TcpListener,
+    udp: Arc<UdpSocket>,
+    script: Arc<Script>,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl MockServer {
+    pub async fn bind(addr: impl ToSocketAddrs, script: Script) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        // Same address as the stream, on whatever port is free.
+        let udp = UdpSocket::bind((listener.local_addr()?.ip(), 0)).await?;
+        Ok(Self {
+            listener,
+            udp: Arc::new(udp),
+            script: Arc::new(script),
+            registry: Arc::new(Mutex::new(Registry::default())),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn spawn(self) -> JoinHandle<io::Result<()>> {
+        tokio::spawn(self.run())
+    }
+
+    pub async fn run(self) -> io::Result<()> {
+        let router = tokio::spawn(route_datagrams(self.udp.clone(), self.registry.clone()));
+        loop {
+            let (stream, peer) = match self.listener.accept().await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    router.abort();
+                    return Err(err);
+                }
+            };
+            let udp = self.udp.clone();
+            let script = self.script.clone();
+            let registry = self.registry.clone();
+            tokio::spawn(async move {
+                if let Err(err) = serve_client(stream, udp, registry, script).await {
+                    println!("Mock server dropped {peer}: {err}");
+                }
+            });
+        }
+    }
+}
+
+// Passwords sent in the clear are turned into a verifier straight away, so
+// both ways of joining check against the same thing.
+struct MockSession {
+    salt: [u8; SALT_SIZE],
+    verifier: [u8; DIGEST_SIZE],
+    has_password: bool,
+    player_limit: u8,
+    visibility: Visibility,
+    players: Vec<u32>,
+}
+
+struct Player {
+    session: Vec<u8>,
+    id: u32,
+    nickname: Vec<u8>,
+}
+
+type DatagramSender = mpsc::UnboundedSender<(RawFrame, SocketAddr)>;
+
+#[derive(Default)]
+struct Registry {
+    sessions: HashMap<Vec<u8>, MockSession>,
+    players: HashMap<u64, Player>,
+    // Bound UDP channels by the token their datagrams start with.
+    datagrams: HashMap<u64, DatagramSender>,
+    next_id: u32,
+}
+
+impl Registry {
+    fn create(&mut self, request: &NewSessionRequest) -> Result<SessionTicket, Reason> {
+        let salt = random_bytes().map_err(|_| Reason::InvalidRequestFormat)?;
+        let password = String::from_utf8_lossy(&request.password);
+        let session = MockSession {
+            salt,
+            verifier: verifier(&salt, &password),
+            has_password: !request.password.is_empty(),
+            player_limit: request.options.player_limit,
+            visibility: request.options.visibility,
+            players: Vec::new(),
+        };
+        self.open(&request.id, &request.nickname, &request.options, session)
+    }
+
+    fn create_verified(
+        &mut self,
+        request: &VerifiedSessionRequest,
+    ) -> Result<SessionTicket, Reason> {
+        let session = MockSession {
+            salt: request.salt,
+            verifier: request.verifier,
+            has_password: request.has_password,
+            player_limit: request.options.player_limit,
+            visibility: request.options.visibility,
+            players: Vec::new(),
+        };
+        self.open(&request.id, &request.nickname, &request.options, session)
+    }
+
+    fn open(
+        &mut self,
+        id: &[u8],
+        nickname: &[u8],
+        options: &SessionOptions,
+        session: MockSession,
+    ) -> Result<SessionTicket, Reason> {
+        if id.is_empty() {
+            return Err(Reason::InvalidIdFormat);
+        }
+        if self.sessions.contains_key(id) {
+            return Err(Reason::IdInUse);
+        }
+        options.validate()?;
+        self.sessions.insert(id.to_vec(), session);
+        Ok(self.admit(id, nickname))
+    }
+
+    fn join(&mut self, request: &JoinSessionRequest) -> Result<SessionTicket, Reason> {
+        let session = self.joinable(&request.id, &request.nickname)?;
+        let password = String::from_utf8_lossy(&request.password);
+        if verifier(&session.salt, &password) != session.verifier {
+            return Err(Reason::WrongPassword);
+        }
+        Ok(self.admit(&request.id, &request.nickname))
+    }
+
+    fn challenge(&self, request: &ChallengeRequest) -> Result<Challenge, Reason> {
+        let session = self.joinable(&request.id, &request.nickname)?;
+        Ok(Challenge {
+            salt: session.salt,
+            nonce: random_bytes().map_err(|_| Reason::InvalidRequestFormat)?,
+        })
+    }
+
+    fn prove(
+        &mut self,
+        request: &ChallengeRequest,
+        nonce: &[u8; NONCE_SIZE],
+        proof: &[u8; DIGEST_SIZE],
+    ) -> Result<SessionTicket, Reason> {
+        // Someone may have taken the nickname since the challenge went out.
+        let session = self.joinable(&request.id, &request.nickname)?;
+        if !check_proof(&session.verifier, nonce, &request.id, &request.nickname, proof) {
+            return Err(Reason::WrongPassword);
+        }
+        Ok(self.admit(&request.id, &request.nickname))
+    }
+
+    fn joinable(&self, id: &[u8], nickname: &[u8]) -> Result<&MockSession, Reason> {
+        let session = self.sessions.get(id).ok_or(Reason::IdDoesntExist)?;
+        let taken = self
+            .players
+            .values()
+            .any(|player| player.session == id && player.nickname == nickname);
+        if taken {
+            return Err(Reason::NicknameInUse);
+        }
+        if session.players.len() >= session.player_limit as usize {
+            return Err(Reason::SessionFull);
+        }
+        Ok(session)
+    }
+
+    fn admit(&mut self, session: &[u8], nickname: &[u8]) -> SessionTicket {
+        self.next_id += 1;
+        let id = self.next_id;
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(id);
+        let resume_token = hasher.finish();
+        self.sessions.get_mut(session).unwrap().players.push(id);
+        self.players.insert(
+            resume_token,
+            Player {
+                session: session.to_vec(),
+                id,
+                nickname: nickname.to_vec(),
+            },
+        );
+        SessionTicket {
+            resume_token,
+            player_id: id,
+        }
+    }
+
+    fn resume(&self, resume_token: u64) -> Result<SessionTicket, Reason> {
+        let player = self.players.get(&resume_token).ok_or(Reason::UnknownResumeToken)?;
+        Ok(SessionTicket {
+            resume_token,
+            player_id: player.id,
+        })
+    }
+
+    fn leave(&mut self, resume_token: u64) {
+        let Some(player) = self.players.remove(&resume_token) else {
+            return;
+        };
+        let Some(session) = self.sessions.get_mut(&player.session) else {
+            return;
+        };
+        session.players.retain(|id| *id != player.id);
+        if session.players.is_empty() {
+            self.sessions.remove(&player.session);
+        }
+    }
+
+    fn bind_udp(&mut self, sender: DatagramSender) -> io::Result<u64> {
+        let token = u64::from_be_bytes(random_bytes()?);
+        self.datagrams.insert(token, sender);
+        Ok(token)
+    }
+
+    fn list(&self) -> SessionList {
+        let sessions = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.visibility == Visibility::Public)
+            .map(|(id, session)| {
+                SessionInfo::new(
+                    id,
+                    session.players.len() as u8,
+                    session.player_limit,
+                    session.has_password,
+                )
+            })
+            .collect();
+        SessionList::new(sessions)
+    }
+}
+
+async fn serve_client(
+    stream: TcpStream,
+    udp: Arc<UdpSocket>,
+    registry: Arc<Mutex<Registry>>,
+    script: Arc<Script>,
+) -> Result<(), FrameError> {
+    let (mut reader, mut writer) = stream.into_split();
+    let hello: Hello = read_frame(&mut reader).await?;
+    if hello.protocol_version != PROTOCOL_VERSION
+        || script.rejection() == Some(Reason::VersionMismatch)
+    {
+        return write_frame(&mut writer, &HelloResponse::Err(Reason::VersionMismatch)).await;
+    }
+    let response = Hello {
+        capabilities: CAP_UDP | CAP_CHALLENGE_AUTH,
+        ..Hello::new()
+    };
+    write_frame(&mut writer, &HelloResponse::Ok(response)).await?;
+
+    // The challenge handed out last, waiting for its proof.
+    let mut pending: Option<(ChallengeRequest, [u8; NONCE_SIZE])> = None;
+    loop {
+        let request: ServerRequest = read_frame(&mut reader).await?;
+        let rejection = script.rejection();
+        let ticket = match request {
+            ServerRequest::NewSession(request) => {
+                let result = match rejection {
+                    Some(reason) => Err(reason),
+                    None => registry.lock().unwrap().create(&request),
+                };
+                match answer_create(&mut writer, result, &script).await? {
+                    Some(ticket) => ticket,
+                    None => continue,
+                }
+            }
+            ServerRequest::NewVerifiedSession(request) => {
+                let result = match rejection {
+                    Some(reason) => Err(reason),
+                    None => registry.lock().unwrap().create_verified(&request),
+                };
+                match answer_create(&mut writer, result, &script).await? {
+                    Some(ticket) => ticket,
+                    None => continue,
+                }
+            }
+            ServerRequest::Challenge(request) => {
+                let result = match rejection {
+                    Some(reason) => Err(reason),
+                    None => registry.lock().unwrap().challenge(&request),
+                };
+                let response = match result {
+                    Ok(challenge) => {
+                        pending = Some((request, challenge.nonce));
+                        ChallengeResponse::Ok(challenge)
+                    }
+                    Err(reason) => ChallengeResponse::Err(reason),
+                };
+                write_frame(&mut writer, &response).await?;
+                continue;
+            }
+            ServerRequest::Proof(request) => {
+                let result = match pending.take() {
+                    Some((challenge, nonce)) => {
+                        registry.lock().unwrap().prove(&challenge, &nonce, &request.proof)
+                    }
+                    None => Err(Reason::InvalidRequestFormat),
+                };
+                match answer_join(&mut writer, result).await? {
+                    Some(ticket) => ticket,
+                    None => continue,
+                }
+            }
+            ServerRequest::JoinSession(request) => {
+                let result = match rejection {
+                    Some(reason) => Err(reason),
+                    None => registry.lock().unwrap().join(&request),
+                };
+                match answer_join(&mut writer, result).await? {
+                    Some(ticket) => ticket,
+                    None => continue,
+                }
+            }
+            ServerRequest::ResumeSession(request) => {
+                let result = match rejection {
+                    Some(reason) => Err(reason),
+                    None => registry.lock().unwrap().resume(request.resume_token),
+                };
+                match answer_join(&mut writer, result).await? {
+                    Some(ticket) => ticket,
+                    None => continue,
+                }
+            }
+            // Only a session has anything to send over UDP.
+            ServerRequest::BindUdp => {
+                let response = UdpBindResponse::Err(Reason::InvalidRequestFormat);
+                write_frame(&mut writer, &response).await?;
+                continue;
+            }
+            ServerRequest::ListSessions => {
+                let list = registry.lock().unwrap().list();
+                write_frame(&mut writer, &list).await?;
+                continue;
+            }
+            ServerRequest::LeaveSession => {
+                write_frame(&mut writer, &LeaveResponse::Err(Reason::IdDoesntExist)).await?;
+                continue;
+            }
+        };
+        if script.failures.contains(&Failure::Disconnect) {
+            return Ok(());
+        }
+        return play(reader, writer, udp, ticket, registry, script).await;
+    }
+}
+
+async fn answer_create(
+    writer: &mut OwnedWriteHalf,
+    result: Result<SessionTicket, Reason>,
+    script: &Script,
+) -> Result<Option<SessionTicket>, FrameError> {
+    match result {
+        Ok(ticket) => {
+            let state = script.snapshots[0].clone();
+            write_frame(writer, &ServerResponse::Ok(ticket, state)).await?;
+            Ok(Some(ticket))
+        }
+        Err(reason) => {
+            write_frame(writer, &ServerResponse::InvalidRequest(reason)).await?;
+            Ok(None)
+        }
+    }
+}
+
+async fn answer_join(
+    writer: &mut OwnedWriteHalf,
+    result: Result<SessionTicket, Reason>,
+) -> Result<Option<SessionTicket>, FrameError> {
+    match result {
+        Ok(ticket) => {
+            write_frame(writer, &JoinResponse::Ok(ticket)).await?;
+            Ok(Some(ticket))
+        }
+        Err(reason) => {
+            write_frame(writer, &JoinResponse::Err(reason)).await?;
+            Ok(None)
+        }
+    }
+}
+
+// The client's end of a bound UDP channel. Where to send to is only known
+// once its first datagram came in.
+struct Datagrams {
+    token: u64,
+    frames: mpsc::UnboundedReceiver<(RawFrame, SocketAddr)>,
+    peer: Option<SocketAddr>,
+}
+
+async fn recv_datagram(datagrams: &mut Option<Datagrams>) -> Option<RawFrame> {
+    let Some(datagrams) = datagrams else {
+        return std::future::pending().await;
+    };
+    let (frame, from) = datagrams.frames.recv().await?;
+    datagrams.peer = Some(from);
+    Some(frame)
+}
+
+// Hands every datagram to the session whose token it starts with. Anything
+// else is dropped, the same as the client does.
+async fn route_datagrams(udp: Arc<UdpSocket>, registry: Arc<Mutex<Registry>>) {
+    let mut buf = vec![0; 65536];
+    loop {
+        let (len, from) = match udp.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                println!("Mock server stopped reading datagrams: {err}");
+                return;
+            }
+        };
+        if len < TOKEN_SIZE {
+            continue;
+        }
+        let (token, frame) = buf[..len].split_at(TOKEN_SIZE);
+        let token = u64::from_be_bytes(token.try_into().unwrap());
+        let Ok(Some((frame, _))) = decode_raw(frame) else {
+            continue;
+        };
+        if let Some(session) = registry.lock().unwrap().datagrams.get(&token) {
+            let _ = session.send((frame, from));
+        }
+    }
+}
+
+// Reading happens on its own task since a frame read cut short by the
+// snapshot timer would lose its bytes. Snapshots go over UDP once the client
+// bound a channel and sent something on it, and over TCP until then.
+async fn play(
+    mut reader: OwnedReadHalf,
+    mut writer: OwnedWriteHalf,
+    udp: Arc<UdpSocket>,
+    ticket: SessionTicket,
+    registry: Arc<Mutex<Registry>>,
+    script: Arc<Script>,
+) -> Result<(), FrameError> {
+    let (frame_tx, mut frames) = mpsc::unbounded_channel::<RawFrame>();
+    let read_task = tokio::spawn(async move {
+        while let Ok(frame) = read_raw_frame(&mut reader).await {
+            if frame_tx.send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    let nickname = registry
+        .lock()
+        .unwrap()
+        .players
+        .get(&ticket.resume_token)
+        .map(|player| player.nickname.clone())
+        .unwrap_or_default();
+    let mut datagrams: Option<Datagrams> = None;
+
+    let result = async {
+        let mut tick = interval(script.tick);
+        let mut last_input = 0;
+        let mut sent = 0u32;
+        loop {
+            let frame = tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(frame) => Some(frame),
+                    // A client that just hangs up keeps its slot for resuming.
+                    None => return Ok(()),
+                },
+                Some(frame) = recv_datagram(&mut datagrams) => Some(frame),
+                _ = tick.tick() => None,
+            };
+            let Some(frame) = frame else {
+                if script.disconnect_after() == Some(sent) {
+                    return Ok(());
+                }
+                if sent > 0 && script.failures.contains(&Failure::TruncatedFrame) {
+                    return write_truncated(&mut writer).await;
+                }
+                let index = sent as usize % script.snapshots.len();
+                let mut snapshot = script.snapshots[index].clone();
+                snapshot.last_input = last_input;
+                snapshot.update().map_err(FrameError::Encode)?;
+                let bound = datagrams.as_ref().and_then(|d| Some((d.token, d.peer?)));
+                match bound {
+                    Some((token, peer)) => {
+                        let mut datagram = token.to_be_bytes().to_vec();
+                        datagram.extend(encode(&snapshot)?);
+                        udp.send_to(&datagram, peer).await?;
+                    }
+                    None => write_frame(&mut writer, &snapshot).await?,
+                }
+                sent += 1;
+                continue;
+            };
+            match frame.kind {
+                MessageKind::PlayerSignal => {
+                    last_input = frame.decode::<PlayerSignal>()?.sequence;
+                }
+                MessageKind::Ping => {
+                    let ping: Ping = frame.decode()?;
+                    write_frame(&mut writer, &Pong { id: ping.id }).await?;
+                }
+                MessageKind::ChatMessage => {
+                    let mut message: ChatMessage = frame.decode()?;
+                    message.sender = nickname.clone();
+                    message.update().map_err(FrameError::Encode)?;
+                    write_frame(&mut writer, &message).await?;
+                }
+                MessageKind::ServerRequest => match frame.decode()? {
+                    ServerRequest::LeaveSession => {
+                        registry.lock().unwrap().leave(ticket.resume_token);
+                        write_frame(&mut writer, &LeaveResponse::Ok).await?;
+                        return Ok(());
+                    }
+                    ServerRequest::BindUdp => {
+                        let (sender, receiver) = mpsc::unbounded_channel();
+                        let token = {
+                            let mut registry = registry.lock().unwrap();
+                            if let Some(old) = datagrams.take() {
+                                registry.datagrams.remove(&old.token);
+                            }
+                            registry.bind_udp(sender)?
+                        };
+                        datagrams = Some(Datagrams {
+                            token,
+                            frames: receiver,
+                            peer: None,
+                        });
+                        let port = udp.local_addr()?.port();
+                        let binding = UdpBinding { token, port };
+                        write_frame(&mut writer, &UdpBindResponse::Ok(binding)).await?;
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+    }
+    .await;
+    read_task.abort();
+    if let Some(datagrams) = datagrams {
+        registry.lock().unwrap().datagrams.remove(&datagrams.token);
+    }
+    result
+}
+
+async fn write_truncated(writer: &mut OwnedWriteHalf) -> Result<(), FrameError> {
+    let mut frame = 64u32.to_be_bytes().to_vec();
+    frame.push(MessageKind::ResponseSignal as u8);
+    frame.extend_from_slice(&[0; 8]);
+    writer.write_all(&frame).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+fn parse_reason(name: &str) -> Option<Reason> {
+    use Reason::*;
+    let reason = match name {
+        "IdInUse" => IdInUse,
+        "InvalidRequestFormat" => InvalidRequestFormat,
+        "InvalidIdFormat" => InvalidIdFormat,
+        "InvalidPassword" => InvalidPassword,
+        "IdDoesntExist" => IdDoesntExist,
+        "WrongPassword" => WrongPassword,
+        "VersionMismatch" => VersionMismatch,
+        "UnknownResumeToken" => UnknownResumeToken,
+        "InvalidPlayerLimit" => InvalidPlayerLimit,
+        "InvalidMap" => InvalidMap,
+        "UnsupportedGameMode" => UnsupportedGameMode,
+        "InvalidRoundTime" => InvalidRoundTime,
+        "InvalidNickname" => InvalidNickname,
+        "NicknameInUse" => NicknameInUse,
+        "SessionFull" => SessionFull,
+        _ => return None,
+    };
+    Some(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth;
+    use crate::codec::read_reply;
+    use crate::connection::Stream;
+    use crate::error::ClientError;
+    use crate::network::handshake;
+
+    async fn start(failures: Vec<Failure>) -> SocketAddr {
+        let script = Script {
+            failures,
+            tick: Duration::from_millis(5),
+            ..Script::default()
+        };
+        let server = MockServer::bind(("127.0.0.1", 0), script).await.unwrap();
+        let addr = server.local_addr().unwrap();
+        server.spawn();
+        addr
+    }
+
+    async fn connect(addr: SocketAddr) -> Result<Stream, ClientError> {
+        let mut stream: Stream = Box::new(TcpStream::connect(addr).await?);
+        assert_eq!(handshake(&mut stream).await?, CAP_UDP | CAP_CHALLENGE_AUTH);
+        Ok(stream)
+    }
+
+    fn options(player_limit: u8) -> SessionOptions {
+        SessionOptions::new(player_limit, "arena", GameMode::FreeForAll, Visibility::Public, 0)
+    }
+
+    async fn create(
+        stream: &mut Stream,
+        request: ServerRequest,
+    ) -> Result<SessionTicket, ClientError> {
+        write_frame(stream, &request).await?;
+        match read_reply(stream).await? {
+            ServerResponse::Ok(ticket, _) => Ok(ticket),
+            ServerResponse::InvalidRequest(reason) => Err(reason.into()),
+        }
+    }
+
+    async fn create_plain(
+        addr: SocketAddr,
+        password: &str,
+        player_limit: u8,
+    ) -> Result<Stream, ClientError> {
+        let mut stream = connect(addr).await?;
+        let request = NewSessionRequest::new("room", password, "ana", options(player_limit));
+        create(&mut stream, ServerRequest::NewSession(request)).await?;
+        Ok(stream)
+    }
+
+    async fn join_plain(
+        addr: SocketAddr,
+        password: &str,
+        nickname: &str,
+    ) -> Result<SessionTicket, ClientError> {
+        let mut stream = connect(addr).await?;
+        let request = JoinSessionRequest::new("room", password, nickname);
+        write_frame(&mut stream, &ServerRequest::JoinSession(request)).await?;
+        match read_reply(&mut stream).await? {
+            JoinResponse::Ok(ticket) => Ok(ticket),
+            JoinResponse::Err(reason) => Err(reason.into()),
+        }
+    }
+
+    async fn join_challenge(
+        addr: SocketAddr,
+        password: &str,
+        nickname: &str,
+    ) -> Result<SessionTicket, ClientError> {
+        let mut stream = connect(addr).await?;
+        auth::join(&mut stream, "room", password, nickname).await
+    }
+
+    fn rejected<T>(result: Result<T, ClientError>) -> Reason {
+        match result {
+            Ok(_) => panic!("expected a rejection"),
+            Err(ClientError::Rejected(reason)) => reason,
+            Err(err) => panic!("expected a rejection, got {err}"),
+        }
+    }
+
+    fn hung_up(result: Result<ResponseSignal, FrameError>) -> bool {
+        matches!(result, Err(FrameError::Io(err)) if err.kind() == io::ErrorKind::UnexpectedEof)
+    }
+
+    #[tokio::test]
+    async fn plain_session_accepts_both_joins() {
+        let addr = start(Vec::new()).await;
+        let _host = create_plain(addr, "secret", 4).await.unwrap();
+        join_plain(addr, "secret", "bob").await.unwrap();
+        join_challenge(addr, "secret", "cat").await.unwrap();
+        assert_eq!(rejected(join_plain(addr, "wrong", "dan").await), Reason::WrongPassword);
+        assert_eq!(rejected(join_challenge(addr, "wrong", "dan").await), Reason::WrongPassword);
+    }
+
+    #[tokio::test]
+    async fn verified_session_accepts_challenge_joins() {
+        let addr = start(Vec::new()).await;
+        let mut host = connect(addr).await.unwrap();
+        let request = VerifiedSessionRequest::new("room", "secret", "ana", options(4)).unwrap();
+        create(&mut host, ServerRequest::NewVerifiedSession(request)).await.unwrap();
+        join_challenge(addr, "secret", "bob").await.unwrap();
+        assert_eq!(rejected(join_challenge(addr, "", "cat").await), Reason::WrongPassword);
+        assert_eq!(rejected(join_challenge(addr, "secret", "ana").await), Reason::NicknameInUse);
+    }
+
+    #[tokio::test]
+    async fn unknown_session_is_refused() {
+        let addr = start(Vec::new()).await;
+        assert_eq!(rejected(join_plain(addr, "", "bob").await), Reason::IdDoesntExist);
+        assert_eq!(rejected(join_challenge(addr, "", "bob").await), Reason::IdDoesntExist);
+    }
+
+    #[tokio::test]
+    async fn full_session_is_refused() {
+        let addr = start(Vec::new()).await;
+        let _host = create_plain(addr, "", 2).await.unwrap();
+        join_plain(addr, "", "bob").await.unwrap();
+        assert_eq!(rejected(join_plain(addr, "", "cat").await), Reason::SessionFull);
+        assert_eq!(rejected(join_challenge(addr, "", "cat").await), Reason::SessionFull);
+    }
+
+    #[tokio::test]
+    async fn scripted_rejections_reach_the_client() {
+        for reason in [Reason::IdInUse, Reason::WrongPassword, Reason::SessionFull] {
+            let addr = start(vec![Failure::Reject(reason)]).await;
+            assert_eq!(rejected(create_plain(addr, "", 4).await), reason);
+            assert_eq!(rejected(join_plain(addr, "", "bob").await), reason);
+            assert_eq!(rejected(join_challenge(addr, "", "bob").await), reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn version_mismatch_fails_the_handshake() {
+        let addr = start(vec![Failure::Reject(Reason::VersionMismatch)]).await;
+        assert_eq!(rejected(connect(addr).await), Reason::VersionMismatch);
+    }
+
+    #[tokio::test]
+    async fn disconnect_hangs_up_on_entering() {
+        let addr = start(vec![Failure::Disconnect]).await;
+        let mut stream = create_plain(addr, "", 4).await.unwrap();
+        assert!(hung_up(read_reply::<ResponseSignal, _>(&mut stream).await));
+    }
+
+    #[tokio::test]
+    async fn disconnect_after_hangs_up_after_snapshots() {
+        let addr = start(vec![Failure::DisconnectAfter(3)]).await;
+        let mut stream = create_plain(addr, "", 4).await.unwrap();
+        for _ in 0..3 {
+            read_reply::<ResponseSignal, _>(&mut stream).await.unwrap();
+        }
+        assert!(hung_up(read_reply::<ResponseSignal, _>(&mut stream).await));
+    }
+
+    #[tokio::test]
+    async fn truncated_frame_ends_in_eof() {
+        let addr = start(vec![Failure::TruncatedFrame]).await;
+        let mut stream = create_plain(addr, "", 4).await.unwrap();
+        read_reply::<ResponseSignal, _>(&mut stream).await.unwrap();
+        assert!(hung_up(read_reply::<ResponseSignal, _>(&mut stream).await));
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::codec::MessageKind;
+use crate::config::{parse_millis, set_parsed};
+
+// A lost frame on the stream still arrives, just one retransmission later.
+const MIN_RETRANSMIT_DELAY: Duration = Duration::from_millis(200);
+// Latency and jitter, capped far beyond anything worth simulating.
+const DELAYS: RangeInclusive<Duration> = Duration::ZERO..=Duration::from_secs(10);
+// Datagrams held back at once. A bandwidth cap below the send rate would
+// otherwise queue them forever, so the oldest are dropped like a full router
+// queue would. Stream frames are never dropped and just wait their turn.
+const MAX_QUEUED_DATAGRAMS: usize = 256;
+
+// Artificial network conditions applied to every frame in both directions.
+// Bandwidth is in bytes per second, 0 meaning unlimited; loss and
+// duplication are chances between 0 and 1.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NetConditions {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub loss: f32,
+    pub duplicate: f32,
+    pub bandwidth: u32,
+}
+
+pub type SharedConditions = Arc<Mutex<NetConditions>>;
+
+impl NetConditions {
+    pub fn is_active(&self) -> bool {
+        *self != NetConditions::default()
+    }
+
+    // Latency and jitter in milliseconds within `DELAYS`, loss and
+    // duplication in percent and bandwidth in kilobits per second.
+    pub fn set(&mut self, key: &str, value: &str) -> bool {
+        match key {
+            "latency" => set_parsed(&mut self.latency, parse_millis(value, DELAYS)),
+            "jitter" => set_parsed(&mut self.jitter, parse_millis(value, DELAYS)),
+            "loss" => set_parsed(&mut self.loss, parse_percent(value)),
+            "duplicate" => set_parsed(&mut self.duplicate, parse_percent(value)),
+            "bandwidth" => set_parsed(
+                &mut self.bandwidth,
+                value.parse::<u32>().ok().map(|kbit| kbit.saturating_mul(1000 / 8)),
+            ),
+            _ => false,
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let bandwidth = match self.bandwidth {
+            0 => "unlimited".to_string(),
+            bytes => format!("{} kbit/s", bytes / (1000 / 8)),
+        };
+        format!(
+            "latency {} ms, jitter {} ms, loss {}%, duplicate {}%, bandwidth {bandwidth}",
+            self.latency.as_millis(),
+            self.jitter.as_millis(),
+            self.loss * 100.0,
+            self.duplicate * 100.0
+        )
+    }
+}
+
+fn parse_percent(value: &str) -> Option<f32> {
+    let percent: f32 = value.trim_end_matches('%').parse().ok()?;
+    (0.0..=100.0).contains(&percent).then_some(percent / 100.0)
+}
+
+// Frames carrying real-time state are sent over UDP when it is available and
+// the protocol copes with them going missing. Everything else is only ever
+// delayed.
+pub fn is_realtime(kind: MessageKind) -> bool {
+    matches!(
+        kind,
+        MessageKind::PlayerSignal
+            | MessageKind::ResponseSignal
+            | MessageKind::SnapshotMessage
+            | MessageKind::CompactState
+    )
+}
+
+// One direction of traffic. Frames are held back until they are due; those
+// sent in order keep their order even when jitter would swap them.
+pub struct Lane<T> {
+    // Each frame with whether it may be dropped to make room.
+    queue: BTreeMap<(Instant, u64), (T, bool)>,
+    datagrams: usize,
+    next_id: u64,
+    busy_until: Instant,
+    last_ordered: Instant,
+    random: Random,
+}
+
+impl<T> Default for Lane<T> {
+    fn default() -> Self {
+        let now = Instant::now();
+        Self {
+            queue: BTreeMap::new(),
+            datagrams: 0,
+            next_id: 0,
+            busy_until: now,
+            last_ordered: now,
+            random: Random::new(),
+        }
+    }
+}
+
+impl<T: Clone> Lane<T> {
+    // `ordered` frames travel over the stream and never overtake each other;
+    // only `realtime` ones that are not, i.e. datagrams, may be dropped or
+    // duplicated.
+    pub fn push(
+        &mut self,
+        frame: T,
+        size: usize,
+        realtime: bool,
+        ordered: bool,
+        conditions: &NetConditions,
+        now: Instant,
+    ) {
+        let unreliable = realtime && !ordered;
+        let lost = self.random.chance(conditions.loss);
+        if lost && unreliable {
+            return;
+        }
+        let copies = if unreliable && self.random.chance(conditions.duplicate) {
+            2
+        } else {
+            1
+        };
+        for _ in 0..copies {
+            let mut due = self.transmit(size, conditions, now) + self.delay(conditions);
+            if lost {
+                due += (conditions.latency * 2).max(MIN_RETRANSMIT_DELAY);
+            }
+            if ordered {
+                due = due.max(self.last_ordered);
+                self.last_ordered = due;
+            }
+            self.queue.insert((due, self.next_id), (frame.clone(), unreliable));
+            self.next_id += 1;
+        }
+        if unreliable {
+            self.datagrams += copies;
+            while self.datagrams > MAX_QUEUED_DATAGRAMS {
+                self.drop_oldest_datagram();
+            }
+        }
+    }
+
+    pub fn next_due(&self) -> Option<Instant> {
+        self.queue.keys().next().map(|(due, _)| *due)
+    }
+
+    pub fn pop_due(&mut self, now: Instant) -> Vec<T> {
+        let mut due = Vec::new();
+        while let Some(entry) = self.queue.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let (frame, unreliable) = entry.remove();
+            self.datagrams -= unreliable as usize;
+            due.push(frame);
+        }
+        due
+    }
+
+    // Oldest by when it was sent, not by when it is due.
+    fn drop_oldest_datagram(&mut self) {
+        let oldest = self
+            .queue
+            .iter()
+            .filter(|(_, (_, unreliable))| *unreliable)
+            .map(|(key, _)| *key)
+            .min_by_key(|(_, id)| *id);
+        if let Some(key) = oldest {
+            self.queue.remove(&key);
+            self.datagrams -= 1;
+        }
+    }
+
+    // When the frame has fully left the sender given the bandwidth cap.
+    fn transmit(&mut self, size: usize, conditions: &NetConditions, now: Instant) -> Instant {
+        if conditions.bandwidth == 0 {
+            return now;
+        }
+        let start = self.busy_until.max(now);
+        let sending = Duration::from_secs_f64(size as f64 / conditions.bandwidth as f64);
+        self.busy_until = start + sending;
+        self.busy_until
+    }
+
+    fn delay(&mut self, conditions: &NetConditions) -> Duration {
+        let spread = conditions.jitter.mul_f32(self.random.next_f32() * 2.0);
+        (conditions.latency + spread).saturating_sub(conditions.jitter)
+    }
+}
+
+// xorshift64*, plenty for deciding which frames to drop.
+struct Random(u64);
+
+impl Random {
+    fn new() -> Self {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(0);
+        Self(hasher.finish() | 1)
+    }
+
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn chance(&mut self, probability: f32) -> bool {
+        probability > 0.0 && self.next_f32() < probability
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conditions(key: &str, value: &str) -> NetConditions {
+        let mut conditions = NetConditions::default();
+        assert!(conditions.set(key, value));
+        conditions
+    }
+
+    // Pushes frames 0..count sent at `now`, all either datagrams or ordered.
+    fn push_all(lane: &mut Lane<u32>, count: u32, ordered: bool, conditions: &NetConditions) {
+        let now = Instant::now();
+        for frame in 0..count {
+            lane.push(frame, 100, true, ordered, conditions, now);
+        }
+    }
+
+    fn delivered(lane: &mut Lane<u32>) -> Vec<u32> {
+        lane.pop_due(Instant::now() + Duration::from_secs(60))
+    }
+
+    #[test]
+    fn loses_datagrams_but_only_delays_the_stream() {
+        let conditions = conditions("loss", "100");
+        let mut lane = Lane::default();
+        push_all(&mut lane, 10, false, &conditions);
+        assert_eq!(lane.next_due(), None);
+
+        let sent = Instant::now();
+        push_all(&mut lane, 10, true, &conditions);
+        assert!(lane.next_due().unwrap() >= sent + MIN_RETRANSMIT_DELAY);
+        assert_eq!(delivered(&mut lane), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn duplicates_datagrams_only() {
+        let conditions = conditions("duplicate", "100");
+        let mut lane = Lane::default();
+        push_all(&mut lane, 3, false, &conditions);
+        assert_eq!(delivered(&mut lane), vec![0, 0, 1, 1, 2, 2]);
+        push_all(&mut lane, 3, true, &conditions);
+        assert_eq!(delivered(&mut lane), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn ordered_frames_keep_their_order_under_jitter() {
+        let mut conditions = conditions("latency", "50");
+        conditions.set("jitter", "40");
+        let mut lane = Lane::default();
+        let sent = Instant::now();
+        push_all(&mut lane, 100, true, &conditions);
+        assert!(lane.pop_due(sent + Duration::from_millis(9)).is_empty());
+        assert!(lane.next_due().unwrap() <= sent + Duration::from_millis(91));
+        assert_eq!(delivered(&mut lane), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn delays_frames_by_the_latency() {
+        let conditions = conditions("latency", "100");
+        let mut lane = Lane::default();
+        let sent = Instant::now();
+        lane.push(1, 100, false, true, &conditions, sent);
+        assert!(lane.pop_due(sent + Duration::from_millis(99)).is_empty());
+        assert_eq!(lane.pop_due(sent + Duration::from_millis(100)), vec![1]);
+    }
+
+    #[test]
+    fn drops_the_oldest_datagrams_once_the_queue_is_full() {
+        // 8 kbit/s sends one 100 byte frame every 100 ms, far too slow to keep up.
+        let conditions = conditions("bandwidth", "8");
+        let mut lane = Lane::default();
+        let count = MAX_QUEUED_DATAGRAMS as u32 + 44;
+        push_all(&mut lane, count, false, &conditions);
+        assert_eq!(delivered(&mut lane), (44..count).collect::<Vec<_>>());
+
+        push_all(&mut lane, count, true, &conditions);
+        assert_eq!(delivered(&mut lane).len(), count as usize);
+    }
+
+    #[test]
+    fn parses_delays_within_bounds() {
+        let mut conditions = NetConditions::default();
+        assert!(conditions.set("latency", "0"));
+        assert!(conditions.set("jitter", "10000"));
+        assert!(!conditions.set("latency", "10001"));
+        assert!(!conditions.set("jitter", "-5"));
+        assert_eq!(conditions.jitter, Duration::from_secs(10));
+        assert!(conditions.set("loss", "2.5%"));
+        assert!(!conditions.set("duplicate", "101"));
+        assert!((conditions.loss - 0.025).abs() < 1e-6);
+    }
+}
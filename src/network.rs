@@ -1,15 +1,44 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
 use crate::*;
+use deku::ctx::Endian;
 use deku::prelude::*;
-use raylib::{math::*, RaylibHandle};
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
-};
+use raylib::math::*;
+use tokio::io::{split, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::{interval, sleep, sleep_until, timeout, MissedTickBehavior};
 
+use self::chat::{ChatLine, ChatMessage};
+use self::codec::{
+    encode, read_reply, write_frame, FrameError, FrameReader, Message, MessageKind, RawFrame,
+    wire_len, HEADER_SIZE, MAX_WIRE_BYTES, MAX_WIRE_ITEMS, WIRE_ENDIAN,
+};
+use self::config::{Config, Transport};
+use self::connection::{secure, Stream};
+use self::datagram::DatagramChannel;
+use self::error::ClientError;
+use self::delta::{BaselineHistory, DeltaError};
+use self::heartbeat::{LinkMonitor, LinkStats};
+use self::netsim::{is_realtime, Lane, SharedConditions};
 use self::objects::NetworkObject;
+use self::quantize::Quantization;
+use self::session::{
+    JoinResponse, LeaveResponse, ResumeRequest, ServerRequest, SessionTicket, MAX_NICKNAME,
+    MAX_PLAYER_LIMIT, MAX_ROUND_TIME, MIN_PLAYER_LIMIT,
+};
 
-#[derive(DekuRead, DekuWrite)]
-#[deku(type = "u8")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
 pub enum Reason {
     #[deku(id = "0x1")]
     IdInUse,
@@ -22,66 +51,206 @@ pub enum Reason {
     #[deku(id = "0x5")]
     IdDoesntExist,
     #[deku(id = "0x6")]
-    WrongPassword
+    WrongPassword,
+    #[deku(id = "0x7")]
+    VersionMismatch,
+    #[deku(id = "0x8")]
+    UnknownResumeToken,
+    #[deku(id = "0x9")]
+    InvalidPlayerLimit,
+    #[deku(id = "0xa")]
+    InvalidMap,
+    #[deku(id = "0xb")]
+    UnsupportedGameMode,
+    #[deku(id = "0xc")]
+    InvalidRoundTime,
+    #[deku(id = "0xd")]
+    InvalidNickname,
+    #[deku(id = "0xe")]
+    NicknameInUse,
+    #[deku(id = "0xf")]
+    SessionFull,
 }
 
-impl ToString for Reason {
-    fn to_string(&self) -> String {
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         use Reason::*;
         match self {
-            IdInUse => "The given ID is already in use.".into(),
-            InvalidRequestFormat => "The request is invalid.".into(),
-            InvalidIdFormat => "The given ID is invalid".into(),
-            InvalidPassword => "The given password is invalid".into(),
-            IdDoesntExist => "There is no session with the given ID".into(),
-            WrongPassword => "The given password is incorrect".into()
+            IdInUse => f.write_str("The given ID is already in use."),
+            InvalidRequestFormat => f.write_str("The request is invalid."),
+            InvalidIdFormat => f.write_str("The given ID is invalid"),
+            InvalidPassword => f.write_str("The given password is invalid"),
+            IdDoesntExist => f.write_str("There is no session with the given ID"),
+            WrongPassword => f.write_str("The given password is incorrect"),
+            VersionMismatch => f.write_str("The server uses a different protocol version"),
+            UnknownResumeToken => f.write_str("The session could not be resumed"),
+            InvalidPlayerLimit => write!(
+                f,
+                "The player limit must be between {MIN_PLAYER_LIMIT} and {MAX_PLAYER_LIMIT}"
+            ),
+            InvalidMap => f.write_str("The map is unknown or its name is invalid"),
+            UnsupportedGameMode => f.write_str("The map does not support this game mode"),
+            InvalidRoundTime => write!(
+                f,
+                "The round time limit must be at most {} minutes",
+                MAX_ROUND_TIME / 60
+            ),
+            InvalidNickname => write!(
+                f,
+                "Nicknames must be 1 to {MAX_NICKNAME} characters without control characters"
+            ),
+            NicknameInUse => f.write_str("Someone in the session already uses this nickname"),
+            SessionFull => f.write_str("The session has reached its player limit"),
         }
     }
 }
 
+pub const PROTOCOL_VERSION: u16 = 7;
+
+pub const CAP_UDP: u32 = 1 << 0;
+pub const CAP_DELTA_SNAPSHOTS: u32 = 1 << 1;
+pub const CAP_QUANTIZED: u32 = 1 << 2;
+pub const CAP_CHALLENGE_AUTH: u32 = 1 << 3;
+pub const CLIENT_CAPABILITIES: u32 =
+    CAP_UDP | CAP_DELTA_SNAPSHOTS | CAP_QUANTIZED | CAP_CHALLENGE_AUTH;
+
 #[derive(DekuRead, DekuWrite)]
-#[deku(type = "u8")]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct Hello {
+    pub protocol_version: u16,
+    pub capabilities: u32,
+}
+
+impl Hello {
+    pub fn new() -> Self {
+        Self {
+            protocol_version: PROTOCOL_VERSION,
+            capabilities: CLIENT_CAPABILITIES,
+        }
+    }
+}
+
+#[derive(DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
+pub enum HelloResponse {
+    #[deku(id = "0x1")]
+    Ok(Hello),
+    #[deku(id = "0x2")]
+    Err(Reason),
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
 pub enum ServerResponse {
     #[deku(id = "0x1")]
-    Ok(ResponseSignal),
+    Ok(SessionTicket, ResponseSignal),
     #[deku(id = "0x2")]
     InvalidRequest(Reason),
 }
-#[derive(Clone, DekuRead, DekuWrite)]
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
 pub struct PlayerSignal {
-    desired_mov: [f32; 3],
-    desired_rot: [f32; 2],
-    camera_radius: f32,
+    pub sequence: u32,
+    pub client_tick: u32,
+    pub desired_mov: [f32; 3],
+    pub desired_rot: [f32; 2],
+    pub camera_radius: f32,
+    pub snapshot_ack: u32,
 }
 
 impl PlayerSignal {
-    pub fn new(desired_mov: Vector3, desired_rot: Vector2, camera_radius: f32) -> Self {
+    pub fn new(
+        sequence: u32,
+        client_tick: u32,
+        desired_mov: Vector3,
+        desired_rot: Vector2,
+        camera_radius: f32,
+    ) -> Self {
         Self {
+            sequence,
+            client_tick,
             desired_mov: desired_mov.to_array(),
             desired_rot: [desired_rot.x, desired_rot.y],
             camera_radius,
+            snapshot_ack: 0,
         }
     }
 }
 
 
-#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
 pub struct ResponseSignal {
-    #[deku(update = "self.players.len()")]
-    pub player_count: usize,
-    #[deku(update = "self.objects.len()")]
-    pub object_count: usize,
+    #[deku(update = "self.players.len()", assert = "*player_count <= MAX_WIRE_ITEMS")]
+    pub player_count: u16,
+    #[deku(update = "self.objects.len()", assert = "*object_count <= MAX_WIRE_ITEMS")]
+    pub object_count: u16,
+    pub last_input: u32,
     pub translation: [f32; 3],
     pub camera_pos: [f32; 3],
     pub camera_target: [f32; 3],
     pub fwd: [f32; 3],
     pub right: [f32; 3],
     #[deku(count = "player_count")]
-    pub players: Vec<ResponseSignal>,
+    pub players: Vec<RemotePlayer>,
     #[deku(count = "object_count")]
     pub objects: Vec<NetworkObject>,
 }
 
+// `id` is assigned when a player enters the session and stays the same until
+// they leave, so it is what ties a player to its entries across snapshots.
+#[derive(Clone, Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct RemotePlayer {
+    pub id: u32,
+    #[deku(update = "self.nickname.len()", assert = "*nickname_count <= MAX_WIRE_BYTES")]
+    nickname_count: u16,
+    #[deku(count = "nickname_count")]
+    pub nickname: Vec<u8>,
+    pub position: [f32; 3],
+    pub rotation: [f32; 4],
+    pub team: u8,
+}
+
+impl RemotePlayer {
+    pub fn new(
+        id: u32,
+        nickname: Vec<u8>,
+        position: [f32; 3],
+        rotation: [f32; 4],
+        team: u8,
+    ) -> Self {
+        Self {
+            id,
+            nickname_count: wire_len(nickname.len()),
+            nickname,
+            position,
+            rotation,
+            team,
+        }
+    }
+
+    // Goes straight to `draw_text`, which cannot take a NUL, so whatever the
+    // server sent is cut down to something a player could have picked.
+    pub fn nickname(&self) -> String {
+        String::from_utf8_lossy(&self.nickname)
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_NICKNAME)
+            .collect()
+    }
+}
+
 impl ResponseSignal {
     pub fn new(
         translation: Vector3,
@@ -93,6 +262,7 @@ impl ResponseSignal {
         Self {
             player_count: 0,
             object_count: 0,
+            last_input: 0,
             translation: translation.to_array(),
             camera_pos: camera_pos.to_array(),
             camera_target: camera_target.to_array(),
@@ -116,23 +286,648 @@ impl Default for ResponseSignal {
     }
 }
 
-pub async fn connect(
-    manager: &mut GameManager,
-    stream: &mut TcpStream,
-    handle: &mut RaylibHandle,
-) -> ResponseSignal {
-    let mut buf = [0; 1024];
-    let update_data = manager.player.update(handle);
-    stream
-        .write(&update_data.to_bytes().unwrap())
-        .await
-        .unwrap();
-    let _response = stream.read(&mut buf).await.unwrap();
-    let parsed = ResponseSignal::from_bytes((&buf, 0)).unwrap();
-    println!("{:?}", parsed.1);
-    parsed.1
+pub enum NetworkEvent {
+    Snapshot(ResponseSignal, Instant),
+    Chat(ChatLine),
+    Reconnecting(u32),
+    Resumed,
+    Disconnected(ClientError),
+}
+
+const RTT_HISTORY: usize = 64;
+// Two seconds of inputs at 60 Hz; anything older is not getting acked.
+const MAX_IN_FLIGHT: usize = 2 * 60;
+
+// Send times of inputs the server has not processed yet. A snapshot names
+// the last input it reflects, which closes out that input's round trip and
+// everything sent before it.
+#[derive(Default)]
+pub struct InputTracker {
+    in_flight: VecDeque<(u32, Instant)>,
+    round_trips: VecDeque<(u32, Duration)>,
+}
+
+impl InputTracker {
+    pub fn sent(&mut self, sequence: u32, at: Instant) {
+        self.in_flight.push_back((sequence, at));
+        if self.in_flight.len() > MAX_IN_FLIGHT {
+            self.in_flight.pop_front();
+        }
+    }
+
+    pub fn acked(&mut self, last_input: u32, at: Instant) {
+        while let Some(&(sequence, sent)) = self.in_flight.front() {
+            if sequence > last_input {
+                break;
+            }
+            self.in_flight.pop_front();
+            if sequence == last_input {
+                self.round_trips.push_back((sequence, at.duration_since(sent)));
+                if self.round_trips.len() > RTT_HISTORY {
+                    self.round_trips.pop_front();
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.in_flight.clear();
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn round_trips(&self) -> impl Iterator<Item = &(u32, Duration)> {
+        self.round_trips.iter()
+    }
+
+    pub fn last_round_trip(&self) -> Option<Duration> {
+        self.round_trips.back().map(|(_, rtt)| *rtt)
+    }
+}
+
+// Everything needed to bring a dropped connection back into the same
+// session: where the server is and the token it issued for resuming.
+pub struct Session {
+    pub config: Config,
+    pub capabilities: u32,
+    pub ticket: SessionTicket,
+}
+
+impl Session {
+    pub async fn open_datagrams(
+        &self,
+        stream: &mut Stream,
+    ) -> Result<Option<DatagramChannel>, FrameError> {
+        if self.config.transport != Transport::Udp || self.capabilities & CAP_UDP == 0 {
+            return Ok(None);
+        }
+        DatagramChannel::open(stream).await
+    }
+
+    async fn resume(&mut self) -> Result<(Stream, Option<DatagramChannel>), ClientError> {
+        let mut stream = get_stream(&self.config).await?;
+        self.capabilities = handshake(&mut stream).await?;
+        let request = ServerRequest::ResumeSession(ResumeRequest::new(self.ticket.resume_token));
+        write_frame(&mut stream, &request).await?;
+        match read_reply(&mut stream).await? {
+            JoinResponse::Ok(ticket) => self.ticket = ticket,
+            JoinResponse::Err(reason) => return Err(ClientError::Rejected(reason)),
+        }
+        let datagrams = self.open_datagrams(&mut stream).await?;
+        Ok((stream, datagrams))
+    }
+}
+
+enum NetworkCommand {
+    Signal(PlayerSignal),
+    Chat(ChatMessage),
+    Leave(oneshot::Sender<()>),
+}
+
+// Owns the in-game connection on a background task so the render loop never
+// waits on the server: commands go out through one channel and decoded
+// snapshots come back through another. With a datagram channel the real-time
+// traffic moves to UDP while the TCP stream stays open for the session. When
+// the connection drops the task reconnects and resumes the session on its own.
+pub struct NetworkTask {
+    commands: mpsc::UnboundedSender<NetworkCommand>,
+    events: mpsc::UnboundedReceiver<NetworkEvent>,
+    state: Arc<ConnectionState>,
+}
+
+impl NetworkTask {
+    pub fn spawn(
+        stream: Stream,
+        datagrams: Option<DatagramChannel>,
+        session: Session,
+        conditions: SharedConditions,
+    ) -> Self {
+        let (commands, command_rx) = mpsc::unbounded_channel();
+        let (event_tx, events) = mpsc::unbounded_channel();
+        let state = Arc::new(ConnectionState {
+            baselines: Mutex::new(BaselineHistory::default()),
+            inputs: Mutex::new(InputTracker::default()),
+            link: Mutex::new(LinkMonitor::default()),
+            quantization: session.config.quantization,
+            heartbeat_interval: session.config.heartbeat_interval,
+            silence_timeout: session.config.silence_timeout,
+            conditions,
+            inbound: Mutex::new(Lane::default()),
+            inbound_ready: Notify::new(),
+        });
+        tokio::spawn(run(stream, datagrams, session, state.clone(), command_rx, event_tx));
+        Self {
+            commands,
+            events,
+            state,
+        }
+    }
+
+    pub fn inputs(&self) -> MutexGuard<'_, InputTracker> {
+        self.state.inputs.lock().unwrap()
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.state.link.lock().unwrap().stats(Instant::now())
+    }
+
+    pub fn send(&self, signal: PlayerSignal) {
+        let _ = self.commands.send(NetworkCommand::Signal(signal));
+    }
+
+    pub fn chat(&self, message: ChatMessage) {
+        let _ = self.commands.send(NetworkCommand::Chat(message));
+    }
+
+    // Asks the server to free our slot right away instead of waiting for the
+    // connection to time out. Never blocks for longer than `LEAVE_TIMEOUT`,
+    // so it is safe to call while the window is closing.
+    pub async fn leave(self) {
+        let (done, acked) = oneshot::channel();
+        if self.commands.send(NetworkCommand::Leave(done)).is_ok() {
+            let _ = timeout(LEAVE_TIMEOUT, acked).await;
+        }
+    }
+
+    pub fn poll(&mut self) -> Vec<NetworkEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = self.events.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+}
+
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+const FIRST_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+async fn run(
+    mut stream: Stream,
+    mut datagrams: Option<DatagramChannel>,
+    mut session: Session,
+    state: Arc<ConnectionState>,
+    mut commands: mpsc::UnboundedReceiver<NetworkCommand>,
+    events: mpsc::UnboundedSender<NetworkEvent>,
+) {
+    loop {
+        let err = match serve(stream, datagrams.as_ref(), &state, &mut commands, &events).await {
+            Ok(()) => return,
+            Err(err) => err,
+        };
+        println!("Lost connection to the server: {err}");
+        match reconnect(&mut session, &events).await {
+            Ok((new_stream, new_datagrams)) => {
+                stream = new_stream;
+                datagrams = new_datagrams;
+                // Whatever was queued while offline is stale by now. A pending
+                // leave is dropped too, which lets its caller return at once.
+                while commands.try_recv().is_ok() {}
+                state.inputs.lock().unwrap().clear();
+                state.baselines.lock().unwrap().reset();
+                if events.send(NetworkEvent::Resumed).is_err() {
+                    return;
+                }
+            }
+            Err(err) => {
+                let _ = events.send(NetworkEvent::Disconnected(err));
+                return;
+            }
+        }
+    }
 }
 
-pub async fn get_stream() -> TcpStream {
-    TcpStream::connect("127.0.0.1:9001").await.unwrap()
+async fn reconnect(
+    session: &mut Session,
+    events: &mpsc::UnboundedSender<NetworkEvent>,
+) -> Result<(Stream, Option<DatagramChannel>), ClientError> {
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let _ = events.send(NetworkEvent::Reconnecting(attempt));
+        sleep(backoff).await;
+        match session.resume().await {
+            Ok(connection) => return Ok(connection),
+            Err(ClientError::Rejected(reason)) => return Err(ClientError::Rejected(reason)),
+            Err(err) if attempt == MAX_RECONNECT_ATTEMPTS => return Err(err),
+            Err(err) => println!("Reconnect attempt {attempt} failed: {err}"),
+        }
+        attempt += 1;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+// Runs one connection until it breaks. Returns `Ok` only when the game side
+// hung up or left the session and the task should stop.
+async fn serve(
+    stream: Stream,
+    datagrams: Option<&DatagramChannel>,
+    state: &ConnectionState,
+    commands: &mut mpsc::UnboundedReceiver<NetworkCommand>,
+    events: &mpsc::UnboundedSender<NetworkEvent>,
+) -> Result<(), FrameError> {
+    state.link.lock().unwrap().reset(Instant::now());
+    *state.inbound.lock().unwrap() = Lane::default();
+    let (reader, writer) = split(stream);
+    let mut reader = FrameReader::new(reader);
+    let outbox = Outbox {
+        writer,
+        datagrams,
+        conditions: &state.conditions,
+        lane: Lane::default(),
+    };
+    let leave = tokio::select! {
+        result = write_signals(outbox, state, commands) => result?,
+        result = read_snapshots(&mut reader, state, events) => return result,
+        result = read_datagrams(datagrams, state, events) => return result,
+        result = deliver_delayed(state, events) => return result,
+    };
+    if let Some(done) = leave {
+        match timeout(LEAVE_TIMEOUT, wait_for_leave(&mut reader)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => println!("Could not leave the session cleanly: {err}"),
+            Err(_) => println!("The server did not acknowledge leaving the session"),
+        }
+        let _ = done.send(());
+    }
+    Ok(())
+}
+
+// Pings go over TCP next to the signals so a server that went quiet is
+// noticed even while the player is not sending any input. Returns the
+// caller to notify once the server acknowledged a leave request.
+async fn write_signals(
+    mut outbox: Outbox<'_>,
+    state: &ConnectionState,
+    commands: &mut mpsc::UnboundedReceiver<NetworkCommand>,
+) -> Result<Option<oneshot::Sender<()>>, FrameError> {
+    let mut heartbeat = interval(state.heartbeat_interval);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            command = commands.recv() => {
+                let mut signal = match command {
+                    Some(NetworkCommand::Signal(signal)) => signal,
+                    Some(NetworkCommand::Chat(message)) => {
+                        outbox.send(&message).await?;
+                        continue;
+                    }
+                    Some(NetworkCommand::Leave(done)) => {
+                        outbox.send(&ServerRequest::LeaveSession).await?;
+                        outbox.drain().await?;
+                        return Ok(Some(done));
+                    }
+                    None => return Ok(None),
+                };
+                signal.snapshot_ack = state.baselines.lock().unwrap().acked();
+                outbox.send(&signal).await?;
+                state.inputs.lock().unwrap().sent(signal.sequence, Instant::now());
+            }
+            _ = sleep_until_due(outbox.lane.next_due()) => outbox.flush_due().await?,
+            _ = heartbeat.tick() => {
+                let now = Instant::now();
+                let ping = {
+                    let mut link = state.link.lock().unwrap();
+                    if link.silent_for(now) > state.silence_timeout {
+                        return Err(FrameError::Io(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "the server stopped responding",
+                        )));
+                    }
+                    link.ping(now)
+                };
+                outbox.send(&ping).await?;
+            }
+        }
+    }
+}
+
+// Outgoing frames. Real-time ones take the datagram channel when there is
+// one. While network conditions are simulated, frames wait in `lane` until
+// they are due.
+struct Outbox<'a> {
+    writer: WriteHalf<Stream>,
+    datagrams: Option<&'a DatagramChannel>,
+    conditions: &'a SharedConditions,
+    lane: Lane<(bool, Vec<u8>)>,
+}
+
+impl Outbox<'_> {
+    async fn send<M: Message>(&mut self, message: &M) -> Result<(), FrameError> {
+        let frame = encode(message)?;
+        let realtime = is_realtime(M::KIND);
+        let datagram = realtime && self.datagrams.is_some();
+        let conditions = *self.conditions.lock().unwrap();
+        if !conditions.is_active() && self.lane.next_due().is_none() {
+            return self.write(datagram, &frame).await;
+        }
+        let size = frame.len();
+        let now = Instant::now();
+        self.lane.push((datagram, frame), size, realtime, !datagram, &conditions, now);
+        Ok(())
+    }
+
+    async fn flush_due(&mut self) -> Result<(), FrameError> {
+        for (datagram, frame) in self.lane.pop_due(Instant::now()) {
+            self.write(datagram, &frame).await?;
+        }
+        Ok(())
+    }
+
+    async fn drain(&mut self) -> Result<(), FrameError> {
+        while let Some(due) = self.lane.next_due() {
+            sleep_until(due.into()).await;
+            self.flush_due().await?;
+        }
+        Ok(())
+    }
+
+    async fn write(&mut self, datagram: bool, frame: &[u8]) -> Result<(), FrameError> {
+        match self.datagrams {
+            Some(channel) if datagram => channel.send_frame(frame).await,
+            _ => {
+                self.writer.write_all(frame).await?;
+                self.writer.flush().await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+async fn sleep_until_due(due: Option<Instant>) {
+    match due {
+        Some(due) => sleep_until(due.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn read_snapshots(
+    reader: &mut FrameReader<ReadHalf<Stream>>,
+    state: &ConnectionState,
+    events: &mpsc::UnboundedSender<NetworkEvent>,
+) -> Result<(), FrameError> {
+    loop {
+        let frame = reader.next().await?;
+        if !receive(frame, true, state, events)? {
+            return Ok(());
+        }
+    }
+}
+
+// Snapshots still in flight are skipped; only the acknowledgement matters.
+async fn wait_for_leave(reader: &mut FrameReader<ReadHalf<Stream>>) -> Result<(), FrameError> {
+    loop {
+        let frame = reader.next().await?;
+        if frame.kind != MessageKind::LeaveResponse {
+            continue;
+        }
+        if let LeaveResponse::Err(reason) = frame.decode()? {
+            eprintln!("Server refused to end the session: {reason}");
+        }
+        return Ok(());
+    }
+}
+
+async fn read_datagrams(
+    datagrams: Option<&DatagramChannel>,
+    state: &ConnectionState,
+    events: &mpsc::UnboundedSender<NetworkEvent>,
+) -> Result<(), FrameError> {
+    let Some(channel) = datagrams else {
+        return std::future::pending().await;
+    };
+    loop {
+        let frame = channel.recv().await?;
+        if !receive(frame, false, state, events)? {
+            return Ok(());
+        }
+    }
+}
+
+// Shared between the network task and the game. Full, delta and quantized
+// snapshots are all rebuilt into a plain `ResponseSignal` here so the render
+// loop never sees the difference.
+struct ConnectionState {
+    baselines: Mutex<BaselineHistory>,
+    inputs: Mutex<InputTracker>,
+    link: Mutex<LinkMonitor>,
+    quantization: Quantization,
+    heartbeat_interval: Duration,
+    silence_timeout: Duration,
+    conditions: SharedConditions,
+    inbound: Mutex<Lane<RawFrame>>,
+    inbound_ready: Notify,
+}
+
+impl ConnectionState {
+    fn decode(
+        &self,
+        frame: RawFrame,
+    ) -> Result<Result<Option<ResponseSignal>, DeltaError>, FrameError> {
+        match frame.kind {
+            MessageKind::SnapshotMessage => {
+                let message = frame.decode()?;
+                Ok(self.baselines.lock().unwrap().receive(message))
+            }
+            MessageKind::CompactState => {
+                Ok(Ok(Some(self.quantization.decompress(&frame.decode()?))))
+            }
+            MessageKind::ResponseSignal => frame.decode().map(|signal| Ok(Some(signal))),
+            // A late reply to some request is no reason to drop the connection.
+            kind => {
+                println!("Ignoring unexpected {kind:?} frame");
+                Ok(Ok(None))
+            }
+        }
+    }
+}
+
+// Hands the frame on right away unless network conditions are simulated, in
+// which case `deliver_delayed` picks it up once it is due.
+fn receive(
+    frame: RawFrame,
+    ordered: bool,
+    state: &ConnectionState,
+    events: &mpsc::UnboundedSender<NetworkEvent>,
+) -> Result<bool, FrameError> {
+    let conditions = *state.conditions.lock().unwrap();
+    let mut inbound = state.inbound.lock().unwrap();
+    if !conditions.is_active() && inbound.next_due().is_none() {
+        drop(inbound);
+        return handle_frame(frame, state, events);
+    }
+    let size = HEADER_SIZE + frame.payload.len();
+    let realtime = is_realtime(frame.kind);
+    inbound.push(frame, size, realtime, ordered, &conditions, Instant::now());
+    state.inbound_ready.notify_one();
+    Ok(true)
+}
+
+async fn deliver_delayed(
+    state: &ConnectionState,
+    events: &mpsc::UnboundedSender<NetworkEvent>,
+) -> Result<(), FrameError> {
+    loop {
+        let due = state.inbound.lock().unwrap().next_due();
+        tokio::select! {
+            _ = sleep_until_due(due) => {}
+            _ = state.inbound_ready.notified() => continue,
+        }
+        let frames = state.inbound.lock().unwrap().pop_due(Instant::now());
+        for frame in frames {
+            if !handle_frame(frame, state, events)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Returns false once nobody is listening for events anymore.
+fn handle_frame(
+    frame: RawFrame,
+    state: &ConnectionState,
+    events: &mpsc::UnboundedSender<NetworkEvent>,
+) -> Result<bool, FrameError> {
+    let received = Instant::now();
+    if frame.kind == MessageKind::Pong {
+        state.link.lock().unwrap().pong(frame.decode()?, received);
+        return Ok(true);
+    }
+    state.link.lock().unwrap().received(received);
+    if frame.kind == MessageKind::ChatMessage {
+        let message: ChatMessage = frame.decode()?;
+        return match ChatLine::try_from(message) {
+            Ok(line) => Ok(events.send(NetworkEvent::Chat(line)).is_ok()),
+            Err(err) => {
+                println!("Dropping chat message: {err}");
+                Ok(true)
+            }
+        };
+    }
+    let snapshot = match state.decode(frame)? {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => return Ok(true),
+        Err(err) => {
+            println!("Dropping snapshot: {err}");
+            return Ok(true);
+        }
+    };
+    state.inputs.lock().unwrap().acked(snapshot.last_input, received);
+    Ok(events.send(NetworkEvent::Snapshot(snapshot, received)).is_ok())
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Tries every address the configured host resolves to, IPv4 and IPv6 alike,
+// and keeps the error of the last one if none of them answer. TLS, when
+// enabled, is set up before anything else is sent.
+pub async fn get_stream(config: &Config) -> io::Result<Stream> {
+    let stream = connect(config).await?;
+    match timeout(CONNECT_TIMEOUT, secure(stream, config)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "the TLS handshake timed out")),
+    }
+}
+
+async fn connect(config: &Config) -> io::Result<TcpStream> {
+    let (host, port) = config.endpoint();
+    let mut last_error = io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{host} did not resolve to any address"),
+    );
+    for address in lookup_host((host, port)).await? {
+        match timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => last_error = err,
+            Err(_) => {
+                last_error = io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connecting to {address} timed out"),
+                )
+            }
+        }
+    }
+    Err(last_error)
+}
+
+// Both sides open with a `Hello` before anything else so a layout change in
+// the wire types is caught here instead of as garbage further down. Returns
+// the capabilities both ends support.
+pub async fn handshake(stream: &mut Stream) -> Result<u32, ClientError> {
+    write_frame(stream, &Hello::new()).await?;
+    match read_reply(stream).await? {
+        HelloResponse::Ok(hello) if hello.protocol_version == PROTOCOL_VERSION => {
+            Ok(hello.capabilities & CLIENT_CAPABILITIES)
+        }
+        HelloResponse::Ok(_) => Err(ClientError::Rejected(Reason::VersionMismatch)),
+        HelloResponse::Err(reason) => Err(ClientError::Rejected(reason)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::codec::testing::{frame_round_trip, reason, response_signal, round_trip};
+
+    prop_compose! {
+        fn player_signal()(
+            sequence in any::<u32>(),
+            client_tick in any::<u32>(),
+            desired_mov in any::<[f32; 3]>(),
+            desired_rot in any::<[f32; 2]>(),
+            camera_radius in any::<f32>(),
+            snapshot_ack in any::<u32>(),
+        ) -> PlayerSignal {
+            PlayerSignal {
+                sequence,
+                client_tick,
+                desired_mov,
+                desired_rot,
+                camera_radius,
+                snapshot_ack,
+            }
+        }
+    }
+
+    fn server_response() -> impl Strategy<Value = ServerResponse> {
+        prop_oneof![
+            (any::<u64>(), any::<u32>(), response_signal()).prop_map(
+                |(resume_token, player_id, state)| {
+                    let ticket = SessionTicket {
+                        resume_token,
+                        player_id,
+                    };
+                    ServerResponse::Ok(ticket, state)
+                }
+            ),
+            reason().prop_map(ServerResponse::InvalidRequest),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn player_signal_round_trips(signal in player_signal()) {
+            frame_round_trip(&signal)?;
+        }
+
+        #[test]
+        fn response_signal_round_trips(signal in response_signal()) {
+            frame_round_trip(&signal)?;
+        }
+
+        #[test]
+        fn server_response_round_trips(response in server_response()) {
+            frame_round_trip(&response)?;
+        }
+
+        #[test]
+        fn reason_round_trips(reason in reason()) {
+            round_trip(&reason)?;
+        }
+    }
 }
@@ -1,17 +1,33 @@
+use deku::ctx::Endian;
 use deku::prelude::*;
 use raylib::math::{Vector3, Vector4};
 use raylib::models::Model;
 
-#[derive(Debug, DekuRead, DekuWrite, Clone)]
+use crate::codec::{wire_len, MAX_WIRE_BYTES, WIRE_ENDIAN};
+use crate::error::ClientError;
+
+#[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
 pub struct NetworkObject {
     pub position: [f32; 3],
     pub rotation: [f32; 4],
-    #[deku(update = "self.id.len()")]
-    id_len: usize,
+    #[deku(update = "self.id.len()", assert = "*id_len <= MAX_WIRE_BYTES")]
+    id_len: u16,
     #[deku(count = "id_len")]
     pub id: Vec<u8>,
 }
 
+impl NetworkObject {
+    pub fn new(position: [f32; 3], rotation: [f32; 4], id: Vec<u8>) -> Self {
+        Self {
+            position,
+            rotation,
+            id_len: wire_len(id.len()),
+            id,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Object {
     pub model: Model,
@@ -27,22 +43,20 @@ impl Object {
         id: String,
         position: [f32; 3],
         rotation: [f32; 4],
-    ) -> Self {
-        Self {
-            id: id.clone(),
-            model: handle
-                .load_model(
-                    thread,
-                    &format!(
-                        "static/models/{}.obj",
-                        id.clone()
-                            .trim_matches(|x: char| x.to_string().parse::<i32>().is_ok())
-                    ),
-                )
-                .unwrap(),
+    ) -> Result<Self, ClientError> {
+        let path = format!(
+            "static/models/{}.obj",
+            id.trim_matches(|x: char| x.to_string().parse::<i32>().is_ok())
+        );
+        let model = handle
+            .load_model(thread, &path)
+            .map_err(|reason| ClientError::Asset { name: path, reason })?;
+        Ok(Self {
+            id,
+            model,
             position: Vector3::new(position[0], position[1], position[2]),
             rotation: Vector4::new(rotation[0], rotation[1], rotation[2], rotation[3]),
-        }
+        })
     }
 
     pub fn update(&mut self, new_state: &NetworkObject) {
@@ -59,3 +73,18 @@ impl Object {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::codec::testing::{network_object, round_trip};
+
+    proptest! {
+        #[test]
+        fn network_object_round_trips(object in network_object()) {
+            round_trip(&object)?;
+        }
+    }
+}
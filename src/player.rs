@@ -1,3 +1,5 @@
+use std::collections::VecDeque;
+
 use crate::*;
 use objects::*;
 use raylib::consts::KeyboardKey::*;
@@ -5,49 +7,164 @@ use raylib::prelude::*;
 
 use self::network::{PlayerSignal, ResponseSignal};
 
+const CORRECTION_DECAY: f32 = 0.85;
+// Two seconds of inputs. A server that stops acknowledging them would
+// otherwise have every snapshot replay an ever longer history.
+const MAX_PENDING_INPUTS: usize = 2 * 60;
+
+// The server integrates every signal's desired_mov as a velocity over one
+// tick and turns the camera by the raw mouse delta times the sensitivity.
+// Prediction mirrors that so replayed inputs land where the server will put
+// them, which only works if these match what the server was started with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Movement {
+    pub tick_rate: f32,
+    pub rotation_sensitivity: f32,
+}
+
+impl Default for Movement {
+    fn default() -> Self {
+        Self {
+            tick_rate: 60.0,
+            rotation_sensitivity: 0.003,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Pose {
+    pub position: Vector3,
+    pub fwd: Vector3,
+    pub right: Vector3,
+    view: Vector3,
+}
+
+impl Pose {
+    fn advance(&mut self, signal: &PlayerSignal, movement: &Movement) {
+        let [x, y, z] = signal.desired_mov;
+        self.position += Vector3::new(x, y, z) / movement.tick_rate;
+        let yaw = -signal.desired_rot[0] * movement.rotation_sensitivity;
+        let pitch = -signal.desired_rot[1] * movement.rotation_sensitivity;
+        self.fwd = rotate_around(self.fwd, Vector3::up(), yaw);
+        self.right = rotate_around(self.right, Vector3::up(), yaw);
+        self.view = rotate_around(rotate_around(self.view, Vector3::up(), yaw), self.right, pitch);
+    }
+}
+
+// Inputs sent but not yet part of a snapshot from the server.
+#[derive(Default)]
+struct Prediction {
+    movement: Movement,
+    pending: VecDeque<PlayerSignal>,
+    last_acked: u32,
+}
+
+impl Prediction {
+    fn push(&mut self, signal: PlayerSignal) {
+        self.pending.push_back(signal);
+        if self.pending.len() > MAX_PENDING_INPUTS {
+            self.pending.pop_front();
+        }
+    }
+
+    // Inputs up to the snapshot's `last_input` are already part of the
+    // authoritative state and get dropped. False for a snapshot older than
+    // one already seen.
+    fn acknowledge(&mut self, last_input: u32) -> bool {
+        if last_input < self.last_acked {
+            return false;
+        }
+        self.last_acked = last_input;
+        while self.pending.front().is_some_and(|signal| signal.sequence <= last_input) {
+            self.pending.pop_front();
+        }
+        true
+    }
+
+    // Where the inputs the server has not seen yet take the acked pose.
+    fn replay(&self, mut pose: Pose) -> Pose {
+        for signal in self.pending.iter() {
+            pose.advance(signal, &self.movement);
+        }
+        pose
+    }
+
+    fn reset(&mut self) {
+        self.pending.clear();
+        self.last_acked = 0;
+    }
+}
+
 pub struct Player {
     pub camera: Camera3D,
     pub object: Object,
-    pub position: Vector3,
-    pub fwd: Vector3,
+    pub pose: Pose,
     camera_radius: f32,
     rotation: Vector3,
     speed: f32,
     pitch: f32,
     yaw: f32,
-    right: Vector3,
+    target_offset: Vector3,
+    correction: Vector3,
+    prediction: Prediction,
+    next_sequence: u32,
+    tick: u32,
+    // Off while the keyboard belongs to something else, e.g. the chat box.
+    pub movement_enabled: bool,
 }
 
 impl Player {
-    pub fn new(camera: Camera3D, speed: f32, object: Object, position: Vector3) -> Self {
+    pub fn new(
+        camera: Camera3D,
+        speed: f32,
+        object: Object,
+        position: Vector3,
+        movement: Movement,
+    ) -> Self {
         Self {
             pitch: 0.0,
             yaw: 0.0,
             camera,
             speed,
-            position,
-            fwd: Vector3::forward(),
-            right: Vector3::right(),
+            pose: Pose {
+                position,
+                fwd: Vector3::forward(),
+                right: Vector3::right(),
+                view: Vector3::forward() * -1.0,
+            },
             object,
             rotation: Vector3::zero(),
             camera_radius: 5.0,
+            target_offset: Vector3::zero(),
+            correction: Vector3::zero(),
+            prediction: Prediction {
+                movement,
+                ..Prediction::default()
+            },
+            next_sequence: 1,
+            tick: 0,
+            movement_enabled: true,
         }
     }
 
     fn get_input(&mut self, rl: &mut RaylibHandle) -> Vector3 {
         let mut movement_vector = Vector3::zero();
-        let fwd = (self.fwd - Vector3::new(0.0, self.fwd.y, 0.0)).normalized();
+        if !self.movement_enabled {
+            return movement_vector;
+        }
+        let fwd = self.pose.fwd;
+        let fwd = (fwd - Vector3::new(0.0, fwd.y, 0.0)).normalized();
         if rl.is_key_down(KEY_W) {
             movement_vector += fwd;
         }
         if rl.is_key_down(KEY_A) {
-            movement_vector -= self.right;
+            movement_vector -= self.pose.right;
         }
         if rl.is_key_down(KEY_S) {
             movement_vector -= fwd;
         }
         if rl.is_key_down(KEY_D) {
-            movement_vector += self.right;
+            movement_vector += self.pose.right;
         }
         if rl.is_key_down(KEY_SPACE) {
             movement_vector += Vector3::up();
@@ -62,8 +179,52 @@ impl Player {
     pub fn update(&mut self, handle: &mut RaylibHandle) -> PlayerSignal {
         let desired_mov = self.get_input(handle);
         let desired_rot = self.update_camera(handle);
-        self.update_radius(handle);
-        PlayerSignal::new(desired_mov, desired_rot, self.camera_radius)
+        if self.movement_enabled {
+            self.update_radius(handle);
+        }
+        self.tick = self.tick.wrapping_add(1);
+        let signal = PlayerSignal::new(
+            self.next_sequence,
+            self.tick,
+            desired_mov,
+            desired_rot,
+            self.camera_radius,
+        );
+        self.next_sequence += 1;
+        self.pose.advance(&signal, &self.prediction.movement);
+        self.prediction.push(signal.clone());
+        self.correction = self.correction * CORRECTION_DECAY;
+        if self.correction.length() < 0.001 {
+            self.correction = Vector3::zero();
+        }
+        self.update_camera_position();
+        signal
+    }
+
+    fn update_camera_position(&mut self) {
+        let target = self.pose.position + self.correction + self.target_offset;
+        self.camera.target = target;
+        self.camera.position = target + self.pose.view * self.camera_radius;
+    }
+
+    // Unacknowledged inputs are replayed on top of the authoritative state.
+    // The visual error is kept in `correction` and decays over the next frames
+    // instead of snapping the camera.
+    pub fn reconcile(&mut self, new_state: ResponseSignal) {
+        if !self.prediction.acknowledge(new_state.last_input) {
+            return;
+        }
+        let shown = self.pose.position + self.correction;
+        self.set_state(new_state);
+        self.pose = self.prediction.replay(self.pose);
+        self.correction = shown - self.pose.position;
+        self.update_camera_position();
+    }
+
+    // Inputs sent before a reconnect never reached the server, and the resumed
+    // session may count them differently, so prediction starts over.
+    pub fn reset_prediction(&mut self) {
+        self.prediction.reset();
     }
 
     pub fn update_camera(&mut self, rl: &mut RaylibHandle) -> Vector2 {
@@ -75,7 +236,7 @@ impl Player {
         self.camera_radius = self.camera_radius.clamp(2.5, 20.0);
     }
     pub fn set_state(&mut self, new_state: ResponseSignal) {
-        self.position = Vector3::new(
+        self.pose.position = Vector3::new(
             new_state.translation[0],
             new_state.translation[1],
             new_state.translation[2],
@@ -90,7 +251,76 @@ impl Player {
             new_state.camera_target[1],
             new_state.camera_target[2],
         );
-        self.fwd = Vector3::new(new_state.fwd[0], new_state.fwd[1], new_state.fwd[2]);
-        self.right = Vector3::new(new_state.right[0], new_state.right[1], new_state.right[2])
+        self.pose.fwd = Vector3::new(new_state.fwd[0], new_state.fwd[1], new_state.fwd[2]);
+        self.pose.right = Vector3::new(new_state.right[0], new_state.right[1], new_state.right[2]);
+        self.target_offset = self.camera.target - self.pose.position;
+        let view = self.camera.position - self.camera.target;
+        if view.length() > 0.0 {
+            self.pose.view = view.normalized();
+        }
+    }
+}
+
+fn rotate_around(vector: Vector3, axis: Vector3, angle: f32) -> Vector3 {
+    let (sin, cos) = angle.sin_cos();
+    vector * cos + axis.cross(vector) * sin + axis * axis.dot(vector) * (1.0 - cos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f32 = 1e-4;
+
+    fn input(sequence: u32, x: f32, turn: f32) -> PlayerSignal {
+        let desired_mov = Vector3::new(x, 0.0, 0.0);
+        PlayerSignal::new(sequence, sequence, desired_mov, Vector2::new(turn, 0.0), 5.0)
+    }
+
+    fn pose(x: f32) -> Pose {
+        Pose {
+            position: Vector3::new(x, 0.0, 0.0),
+            fwd: Vector3::forward(),
+            right: Vector3::right(),
+            view: Vector3::forward() * -1.0,
+        }
+    }
+
+    fn prediction(tick_rate: f32, rotation_sensitivity: f32) -> Prediction {
+        Prediction {
+            movement: Movement {
+                tick_rate,
+                rotation_sensitivity,
+            },
+            ..Prediction::default()
+        }
+    }
+
+    #[test]
+    fn replays_pending_inputs_over_the_acked_state() {
+        let mut prediction = prediction(30.0, 0.003);
+        for sequence in 1..=5 {
+            prediction.push(input(sequence, 15.0, 0.0));
+        }
+        // The server applied the first three and then moved the player on
+        // its own, e.g. pushed by another one.
+        assert!(prediction.acknowledge(3));
+        let replayed = prediction.replay(pose(100.0));
+        assert!((replayed.position.x - 101.0).abs() < TOLERANCE);
+
+        assert!(!prediction.acknowledge(2));
+        assert_eq!(prediction.pending.len(), 2);
+        assert!(prediction.acknowledge(5));
+        assert!((prediction.replay(pose(7.0)).position.x - 7.0).abs() < TOLERANCE);
+    }
+
+    #[test]
+    fn turns_by_the_configured_sensitivity() {
+        let mut prediction = prediction(60.0, 0.01);
+        prediction.push(input(1, 0.0, -std::f32::consts::FRAC_PI_2 * 100.0));
+        let replayed = prediction.replay(pose(0.0));
+        let turned = rotate_around(Vector3::forward(), Vector3::up(), std::f32::consts::FRAC_PI_2);
+        assert!((replayed.fwd - turned).length() < TOLERANCE);
+        assert!((replayed.fwd - Vector3::forward()).length() > 1.0);
     }
 }
@@ -0,0 +1,400 @@
+use deku::ctx::Endian;
+use deku::prelude::*;
+
+use crate::codec::{wire_len, MAX_WIRE_BYTES, MAX_WIRE_ITEMS, WIRE_ENDIAN};
+use crate::network::{RemotePlayer, ResponseSignal};
+use crate::objects::NetworkObject;
+
+pub const MAX_POSITION_BITS: u8 = 16;
+pub const MAX_ROTATION_BITS: u8 = 10;
+
+const DIRECTION_SCALE: f32 = i16::MAX as f32;
+
+// Both ends have to agree on these, so they come from the client config and
+// must match what the server was started with.
+#[derive(Clone, Copy, Debug)]
+pub struct Quantization {
+    pub world_min: [f32; 3],
+    pub world_max: [f32; 3],
+    pub position_bits: u8,
+    pub rotation_bits: u8,
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Self {
+            world_min: [-512.0; 3],
+            world_max: [512.0; 3],
+            position_bits: MAX_POSITION_BITS,
+            rotation_bits: MAX_ROTATION_BITS,
+        }
+    }
+}
+
+impl Quantization {
+    fn position_steps(&self) -> f32 {
+        ((1u32 << self.position_bits) - 1) as f32
+    }
+
+    fn rotation_steps(&self) -> f32 {
+        ((1u32 << self.rotation_bits) - 1) as f32
+    }
+
+    // Largest distance between a position inside the world bounds and its
+    // decoded value, per axis.
+    pub fn position_error(&self) -> [f32; 3] {
+        let mut error = [0.0; 3];
+        for axis in 0..3 {
+            let range = self.world_max[axis] - self.world_min[axis];
+            error[axis] = range / self.position_steps() / 2.0;
+        }
+        error
+    }
+
+    // Largest error of the three quaternion components that go over the wire.
+    // The dropped one is rebuilt from them, so its error is a few times that.
+    pub fn rotation_error(&self) -> f32 {
+        std::f32::consts::SQRT_2 / self.rotation_steps() / 2.0
+    }
+
+    pub fn encode_position(&self, position: [f32; 3]) -> [u16; 3] {
+        let mut encoded = [0; 3];
+        for axis in 0..3 {
+            let (min, max) = (self.world_min[axis], self.world_max[axis]);
+            let normalized = ((position[axis] - min) / (max - min)).clamp(0.0, 1.0);
+            encoded[axis] = (normalized * self.position_steps()).round() as u16;
+        }
+        encoded
+    }
+
+    pub fn decode_position(&self, encoded: [u16; 3]) -> [f32; 3] {
+        let mut position = [0.0; 3];
+        for axis in 0..3 {
+            let (min, max) = (self.world_min[axis], self.world_max[axis]);
+            position[axis] = min + encoded[axis] as f32 / self.position_steps() * (max - min);
+        }
+        position
+    }
+
+    // Smallest-three: the largest component is dropped (its sign is folded
+    // into the others since q and -q are the same rotation) and rebuilt from
+    // the unit length. The top two bits hold its index, the remaining
+    // components follow with `rotation_bits` each.
+    pub fn encode_rotation(&self, rotation: [f32; 4]) -> u32 {
+        let length = rotation.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let mut rotation = if length > 0.0 {
+            rotation.map(|x| x / length)
+        } else {
+            [0.0, 0.0, 0.0, 1.0]
+        };
+        let largest = (0..4)
+            .max_by(|a, b| rotation[*a].abs().total_cmp(&rotation[*b].abs()))
+            .unwrap();
+        if rotation[largest] < 0.0 {
+            rotation = rotation.map(|x| -x);
+        }
+
+        let bits = self.rotation_bits as u32;
+        let mut packed = (largest as u32) << 30;
+        let mut shift = bits * 2;
+        for (i, component) in rotation.iter().enumerate() {
+            if i == largest {
+                continue;
+            }
+            let normalized = (component * std::f32::consts::FRAC_1_SQRT_2 + 0.5).clamp(0.0, 1.0);
+            packed |= ((normalized * self.rotation_steps()).round() as u32) << shift;
+            shift = shift.saturating_sub(bits);
+        }
+        packed
+    }
+
+    pub fn decode_rotation(&self, packed: u32) -> [f32; 4] {
+        let bits = self.rotation_bits as u32;
+        let mask = (1 << bits) - 1;
+        let largest = (packed >> 30) as usize;
+        let mut rotation = [0.0; 4];
+        let mut shift = bits * 2;
+        let mut sum = 0.0;
+        for (i, component) in rotation.iter_mut().enumerate() {
+            if i == largest {
+                continue;
+            }
+            let normalized = ((packed >> shift) & mask) as f32 / self.rotation_steps();
+            *component = (normalized - 0.5) * std::f32::consts::SQRT_2;
+            sum += *component * *component;
+            shift = shift.saturating_sub(bits);
+        }
+        rotation[largest] = (1.0 - sum).max(0.0).sqrt();
+        rotation
+    }
+
+    pub fn compress(&self, state: &ResponseSignal) -> CompactState {
+        let players = state
+            .players
+            .iter()
+            .map(|player| {
+                CompactPlayer::new(
+                    player.id,
+                    player.nickname.clone(),
+                    self.encode_position(player.position),
+                    self.encode_rotation(player.rotation),
+                    player.team,
+                )
+            })
+            .collect::<Vec<_>>();
+        let objects = state
+            .objects
+            .iter()
+            .map(|object| {
+                CompactObject::new(
+                    self.encode_position(object.position),
+                    self.encode_rotation(object.rotation),
+                    object.id.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        CompactState {
+            player_count: wire_len(players.len()),
+            object_count: wire_len(objects.len()),
+            last_input: state.last_input,
+            translation: self.encode_position(state.translation),
+            camera_pos: self.encode_position(state.camera_pos),
+            camera_target: self.encode_position(state.camera_target),
+            fwd: encode_direction(state.fwd),
+            right: encode_direction(state.right),
+            players,
+            objects,
+        }
+    }
+
+    pub fn decompress(&self, state: &CompactState) -> ResponseSignal {
+        let mut signal = ResponseSignal::default();
+        signal.last_input = state.last_input;
+        signal.translation = self.decode_position(state.translation);
+        signal.camera_pos = self.decode_position(state.camera_pos);
+        signal.camera_target = self.decode_position(state.camera_target);
+        signal.fwd = decode_direction(state.fwd);
+        signal.right = decode_direction(state.right);
+        signal.players = state
+            .players
+            .iter()
+            .map(|player| {
+                RemotePlayer::new(
+                    player.id,
+                    player.nickname.clone(),
+                    self.decode_position(player.position),
+                    self.decode_rotation(player.rotation),
+                    player.team,
+                )
+            })
+            .collect();
+        signal.objects = state
+            .objects
+            .iter()
+            .map(|object| {
+                NetworkObject::new(
+                    self.decode_position(object.position),
+                    self.decode_rotation(object.rotation),
+                    object.id.clone(),
+                )
+            })
+            .collect();
+        signal.player_count = wire_len(signal.players.len());
+        signal.object_count = wire_len(signal.objects.len());
+        signal
+    }
+}
+
+pub fn encode_direction(direction: [f32; 3]) -> [i16; 3] {
+    direction.map(|x| (x.clamp(-1.0, 1.0) * DIRECTION_SCALE).round() as i16)
+}
+
+pub fn decode_direction(encoded: [i16; 3]) -> [f32; 3] {
+    encoded.map(|x| x as f32 / DIRECTION_SCALE)
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct CompactObject {
+    pub position: [u16; 3],
+    pub rotation: u32,
+    #[deku(update = "self.id.len()", assert = "*id_len <= MAX_WIRE_BYTES")]
+    id_len: u16,
+    #[deku(count = "id_len")]
+    pub id: Vec<u8>,
+}
+
+impl CompactObject {
+    pub fn new(position: [u16; 3], rotation: u32, id: Vec<u8>) -> Self {
+        Self {
+            position,
+            rotation,
+            id_len: wire_len(id.len()),
+            id,
+        }
+    }
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct CompactPlayer {
+    pub id: u32,
+    #[deku(update = "self.nickname.len()", assert = "*nickname_count <= MAX_WIRE_BYTES")]
+    nickname_count: u16,
+    #[deku(count = "nickname_count")]
+    pub nickname: Vec<u8>,
+    pub position: [u16; 3],
+    pub rotation: u32,
+    pub team: u8,
+}
+
+impl CompactPlayer {
+    pub fn new(id: u32, nickname: Vec<u8>, position: [u16; 3], rotation: u32, team: u8) -> Self {
+        Self {
+            id,
+            nickname_count: wire_len(nickname.len()),
+            nickname,
+            position,
+            rotation,
+            team,
+        }
+    }
+}
+
+// Same content as `ResponseSignal` with positions quantized against the
+// world bounds, directions as fixed point and rotations in smallest-three.
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct CompactState {
+    #[deku(update = "self.players.len()", assert = "*player_count <= MAX_WIRE_ITEMS")]
+    pub player_count: u16,
+    #[deku(update = "self.objects.len()", assert = "*object_count <= MAX_WIRE_ITEMS")]
+    pub object_count: u16,
+    pub last_input: u32,
+    pub translation: [u16; 3],
+    pub camera_pos: [u16; 3],
+    pub camera_target: [u16; 3],
+    pub fwd: [i16; 3],
+    pub right: [i16; 3],
+    #[deku(count = "player_count")]
+    pub players: Vec<CompactPlayer>,
+    #[deku(count = "object_count")]
+    pub objects: Vec<CompactObject>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rounding in f32 on top of the quantization itself.
+    const TOLERANCE: f32 = 1e-4;
+
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> f32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 40) as f32 / (1u64 << 24) as f32
+        }
+
+        fn between(&mut self, min: f32, max: f32) -> f32 {
+            min + self.next() * (max - min)
+        }
+
+        // Uniform over rotations, with the largest component made positive
+        // the way the encoding folds the sign.
+        fn rotation(&mut self) -> [f32; 4] {
+            loop {
+                let q = [(); 4].map(|_| self.between(-1.0, 1.0));
+                let length = q.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if !(0.1..=1.0).contains(&length) {
+                    continue;
+                }
+                let q = q.map(|x| x / length);
+                let largest = q.iter().copied().max_by(|a, b| a.abs().total_cmp(&b.abs()));
+                return if largest.unwrap() < 0.0 { q.map(|x| -x) } else { q };
+            }
+        }
+    }
+
+    #[test]
+    fn positions_stay_within_the_error_bound() {
+        let mut random = Random(0x9e37_79b9_7f4a_7c15);
+        for bits in 1..=MAX_POSITION_BITS {
+            let quantization = Quantization {
+                world_min: [-512.0, -64.0, 0.0],
+                world_max: [512.0, 64.0, 100.0],
+                position_bits: bits,
+                ..Quantization::default()
+            };
+            let error = quantization.position_error();
+            for _ in 0..1000 {
+                let position = [
+                    random.between(-512.0, 512.0),
+                    random.between(-64.0, 64.0),
+                    random.between(0.0, 100.0),
+                ];
+                let decoded =
+                    quantization.decode_position(quantization.encode_position(position));
+                for axis in 0..3 {
+                    let off = (decoded[axis] - position[axis]).abs();
+                    assert!(
+                        off <= error[axis] + TOLERANCE,
+                        "{bits} bits: {position:?} -> {decoded:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn positions_outside_the_world_are_clamped() {
+        let quantization = Quantization::default();
+        let decoded = quantization.decode_position(quantization.encode_position([-1e6, 1e6, 0.0]));
+        assert_eq!(decoded[0], -512.0);
+        assert_eq!(decoded[1], 512.0);
+    }
+
+    #[test]
+    fn rotations_stay_within_the_error_bound() {
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+        for bits in 1..=MAX_ROTATION_BITS {
+            let quantization = Quantization {
+                rotation_bits: bits,
+                ..Quantization::default()
+            };
+            let error = quantization.rotation_error();
+            // The rebuilt component L is at least 1/2 and moves by
+            // |sum c'^2 - sum c^2| / L, at most
+            // (2 * error * sum |c| + 3 * error^2) / L with
+            // sum |c| <= sqrt(3 * (1 - L^2)), which peaks at L = 1/2.
+            let rebuilt_error = 6.0 * error * (1.0 + error);
+            for _ in 0..1000 {
+                let rotation = random.rotation();
+                let largest = (0..4)
+                    .max_by(|a, b| rotation[*a].abs().total_cmp(&rotation[*b].abs()))
+                    .unwrap();
+                let decoded =
+                    quantization.decode_rotation(quantization.encode_rotation(rotation));
+                for i in 0..4 {
+                    let off = (decoded[i] - rotation[i]).abs();
+                    let bound = if i == largest { rebuilt_error } else { error };
+                    assert!(
+                        off <= bound + TOLERANCE,
+                        "{bits} bits: {rotation:?} -> {decoded:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rotations_keep_their_sign_folded() {
+        let quantization = Quantization::default();
+        let rotation = [0.0, 0.0, -0.6, -0.8];
+        let decoded = quantization.decode_rotation(quantization.encode_rotation(rotation));
+        assert!(decoded[3] > 0.0 && decoded[2] > 0.0);
+    }
+}
@@ -5,19 +5,25 @@ use std::{
 
 use raylib::math::Vector3;
 
-pub fn build_models(scene_path: &str, mtl_path: &str) {
-    let data = std::fs::read_to_string(scene_path).unwrap();
-    let material = std::fs::read_to_string(mtl_path).unwrap();
+use crate::error::ClientError;
+
+pub fn build_models(scene_path: &str, mtl_path: &str) -> Result<(), ClientError> {
+    let data = std::fs::read_to_string(scene_path)?;
+    let material = std::fs::read_to_string(mtl_path)?;
     let mut object_name = String::new();
-    let mut model_file = File::create("static/models/none").unwrap();
-    let mut material_file = File::create("static/models/none").unwrap();
+    let mut model_file = File::create("static/models/none")?;
+    let mut material_file = File::create("static/models/none")?;
     let mut last = String::new();
     let mut new_file = true;
     for line in data.lines() {
         let tokens = line.split_whitespace().collect::<Vec<&str>>();
-        match tokens[0] {
+        let Some(&keyword) = tokens.first() else {
+            continue;
+        };
+        match keyword {
             "o" => {
-                new_file = create_object(&mut last, &mut model_file, &mut object_name, tokens);
+                let name = argument(line, &tokens)?;
+                new_file = create_object(&mut last, &mut model_file, &mut object_name, name)?;
             }
             "usemtl" => {
                 if !new_file {
@@ -29,23 +35,22 @@ pub fn build_models(scene_path: &str, mtl_path: &str) {
                     &material,
                     &mut object_name,
                     tokens,
-                )
+                )?
             }
             _ => {
                 if !new_file {
                     continue;
                 }
-                model_file.write(tokens[0..].join(" ").as_bytes()).unwrap();
-                model_file.write("\n".as_bytes()).unwrap();
+                model_file.write_all(tokens[0..].join(" ").as_bytes())?;
+                model_file.write_all("\n".as_bytes())?;
             }
         }
     }
-    println!("{last}");
-    let data = std::fs::read_to_string(format!("static/models/{}.obj", &last)).unwrap();
-    let mut vertices = get_vertices(&data);
+    let data = std::fs::read_to_string(format!("static/models/{}.obj", &last))?;
+    let mut vertices = get_vertices(&data)?;
     move_to_origin(&mut vertices);
-    update_file(format!("static/models/{}.obj", &last), data, vertices);
-    fix_faces(format!("static/models/{}.obj", last));
+    update_file(format!("static/models/{}.obj", &last), data, vertices)?;
+    fix_faces(format!("static/models/{}.obj", last))
 }
 
 fn create_material(
@@ -54,16 +59,21 @@ fn create_material(
     material: &String,
     object_name: &mut String,
     tokens: Vec<&str>,
-) {
-    model_file.write(tokens.join(" ").as_bytes()).unwrap();
-    model_file.write("\n".as_bytes()).unwrap();
-    *material_file = File::create(format!("static/models/{}.mtl", object_name)).unwrap();
+) -> Result<(), ClientError> {
+    let line = tokens.join(" ");
+    let name = argument(&line, &tokens)?;
+    model_file.write_all(line.as_bytes())?;
+    model_file.write_all("\n".as_bytes())?;
+    *material_file = File::create(format!("static/models/{}.mtl", object_name))?;
     let header = "# Blender 4.0.2 MTL File: 'None'\n# www.blender.org\n\n";
-    material_file.write(header.as_bytes()).unwrap();
+    material_file.write_all(header.as_bytes())?;
     let begin = material
         .lines()
-        .position(|x| x == &format!("newmtl {}", tokens[1]))
-        .unwrap();
+        .position(|x| x == &format!("newmtl {}", name))
+        .ok_or_else(|| ClientError::Asset {
+            name: format!("static/models/{}.mtl", object_name),
+            reason: format!("material {} is missing", name),
+        })?;
     let mut end = 0;
 
     for line in material.lines().collect::<Vec<&str>>()[begin..].iter() {
@@ -78,23 +88,69 @@ fn create_material(
     }
     let material_data = &material.lines().collect::<Vec<&str>>()[begin..end];
     let material_data = material_data.join("\n");
-    material_file.write(material_data.as_bytes()).unwrap();
+    material_file.write_all(material_data.as_bytes())?;
+    Ok(())
 }
 
-fn get_vertices(file: &str) -> Vec<Vec<f32>> {
+fn get_vertices(file: &str) -> Result<Vec<Vec<f32>>, ClientError> {
     let mut lines = Vec::<Vec<f32>>::new();
     for line in file.lines() {
-        if &line[0..2] != "v " {
+        if !line.starts_with("v ") {
             continue;
         }
         let vertices = &line.split_whitespace().collect::<Vec<&str>>()[1..];
         let vertices = vertices
             .into_iter()
-            .map(|x| x.parse::<f32>().unwrap())
-            .collect::<Vec<f32>>();
+            .map(|x| x.parse::<f32>().map_err(|err| invalid_model(line, err)))
+            .collect::<Result<Vec<f32>, _>>()?;
+        if vertices.len() < 3 {
+            return Err(invalid_model(line, "fewer than three coordinates"));
+        }
         lines.push(vertices);
     }
-    lines
+    Ok(lines)
+}
+
+// The token after the keyword, e.g. the name in `o name`.
+fn argument<'a>(line: &str, tokens: &[&'a str]) -> Result<&'a str, ClientError> {
+    tokens
+        .get(1)
+        .copied()
+        .ok_or_else(|| invalid_model(line, "missing name"))
+}
+
+// Every vertex of a face as its position/texture/normal indices. Only the
+// position is required, so `1`, `1/2`, `1//3` and `1/2/3` are all accepted.
+fn parse_face(face: &str) -> Result<Vec<[Option<u32>; 3]>, ClientError> {
+    let vertices = face
+        .split_whitespace()
+        .map(|vertex| {
+            let mut indices = [None; 3];
+            for (i, index) in vertex.split('/').enumerate() {
+                if i >= indices.len() {
+                    return Err(invalid_model(face, "more than three indices"));
+                }
+                if !index.is_empty() {
+                    indices[i] = Some(index.parse().map_err(|err| invalid_model(face, err))?);
+                }
+            }
+            if indices[0].is_none() {
+                return Err(invalid_model(face, "missing position index"));
+            }
+            Ok(indices)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if vertices.is_empty() {
+        return Err(invalid_model(face, "face without vertices"));
+    }
+    Ok(vertices)
+}
+
+fn invalid_model(line: &str, err: impl std::fmt::Display) -> ClientError {
+    ClientError::Asset {
+        name: "model data".into(),
+        reason: format!("{err} in `{line}`"),
+    }
 }
 
 fn move_to_origin(vertices: &mut Vec<Vec<f32>>) -> Vector3 {
@@ -139,40 +195,40 @@ fn create_object(
     last: &mut String,
     model_file: &mut File,
     object_name: &mut String,
-    tokens: Vec<&str>,
-) -> bool {
-    let name = tokens[1].split("-").collect::<Vec<&str>>()[0]
+    name: &str,
+) -> Result<bool, ClientError> {
+    let name = name.split("-").collect::<Vec<&str>>()[0]
         .trim_matches(|x: char| x.to_string().parse::<i32>().is_ok());
     let new_path = format!("static/models/{}.obj", name);
     let read = std::fs::read_to_string(&new_path);
     if read.is_ok() {
-        return false;
+        return Ok(false);
     }
     let path = format!("static/models/{}.obj", last);
     if std::fs::read(&path).is_err() {
-        File::create(&path).unwrap();
+        File::create(&path)?;
     }
-    let read = std::fs::read_to_string(&path).unwrap();
-    let mut vertices = get_vertices(&read);
+    let read = std::fs::read_to_string(&path)?;
+    let mut vertices = get_vertices(&read)?;
     move_to_origin(&mut vertices);
-    update_file(path, read, vertices);
-    fix_faces(format!("static/models/{}.obj", last));
-    *model_file = File::create(&new_path).unwrap();
+    update_file(path, read, vertices)?;
+    fix_faces(format!("static/models/{}.obj", last))?;
+    *model_file = File::create(&new_path)?;
     *last = name.into();
     *object_name = name.into();
     let header = format!(
         "# Blender 4.0.2\n# www.blender.org\nmtllib {}.mtl\no {}\n",
         name, name
     );
-    model_file.write(header.as_bytes()).unwrap();
-    true
+    model_file.write_all(header.as_bytes())?;
+    Ok(true)
 }
 
-fn update_file(path: String, data: String, vertices: Vec<Vec<f32>>) {
+fn update_file(path: String, data: String, vertices: Vec<Vec<f32>>) -> Result<(), ClientError> {
     let mut new_data = String::new();
     let mut count = 0;
     for line in data.lines() {
-        if &line[0..2] != "v " {
+        if !line.starts_with("v ") {
             new_data.push_str(line);
         } else {
             new_data.push_str("v ");
@@ -187,104 +243,86 @@ fn update_file(path: String, data: String, vertices: Vec<Vec<f32>>) {
         }
         new_data.push_str("\n");
     }
-    write(path, new_data).unwrap();
+    write(path, new_data)?;
+    Ok(())
 }
 
-fn fix_faces(model_path: String) {
-    if let Err(_buf) = read_to_string(model_path.clone()) {
-        return;
-    }
-    let buf = read_to_string(model_path.clone()).unwrap();
+fn fix_faces(model_path: String) -> Result<(), ClientError> {
+    let Ok(buf) = read_to_string(model_path.clone()) else {
+        return Ok(());
+    };
     if buf.len() < 1 {
-        return;
+        return Ok(());
     }
     let clone = buf.clone();
     let lines = clone.lines().collect::<Vec<&str>>();
-    let mut faces = get_faces(lines);
-    let fixed = fix_vertices(&mut faces, buf);
+    let faces = get_faces(lines);
+    let fixed = fix_vertices(&faces, buf)?;
 
-    write(model_path, fixed).unwrap();
+    write(model_path, fixed)?;
+    Ok(())
 }
 
 fn get_faces(lines: Vec<&str>) -> Vec<&str> {
     lines
         .into_iter()
-        .filter(|x| &x[0..1] == "f")
+        .filter(|x| x.starts_with('f'))
         .map(|x| &x[1..])
         .collect::<Vec<&str>>()
         .to_vec()
 }
 
-fn get_min_vertices(faces: &mut Vec<&str>) -> (u32, u32, u32) {
-    let (mut min1, mut min2, mut min3) = (u32::MAX, u32::MAX, u32::MAX);
+fn get_min_vertices(faces: &[&str]) -> Result<[u32; 3], ClientError> {
+    let mut mins = [u32::MAX; 3];
     for face in faces.iter() {
-        let aux = face.split_whitespace().collect::<Vec<&str>>();
-        let aux = aux
-            .iter()
-            .map(|x| {
-                x.split("/")
-                    .map(|x| x.parse::<u32>().unwrap())
-                    .collect::<Vec<u32>>()
-            })
-            .collect::<Vec<Vec<u32>>>();
-        let mut f1 = aux.iter().map(|x| x[0]).collect::<Vec<u32>>();
-        let mut f2 = aux.iter().map(|x| x[1]).collect::<Vec<u32>>();
-        let mut f3 = aux.iter().map(|x| x[2]).collect::<Vec<u32>>();
-        f1.sort();
-        f2.sort();
-        f3.sort();
-        min1 = std::cmp::min(min1, f1[0]);
-        min2 = std::cmp::min(min2, f2[0]);
-        min3 = std::cmp::min(min3, f3[0]);
+        for vertex in parse_face(face)? {
+            for (min, index) in mins.iter_mut().zip(vertex) {
+                if let Some(index) = index {
+                    *min = (*min).min(index);
+                }
+            }
+        }
     }
-    println!("{}|{}|{}", min1, min2, min3);
-    (min1, min2, min3)
+    Ok(mins)
 }
 
-fn fix_vertices(faces: &mut Vec<&str>, buf: String) -> String {
+// Renumbers the indices to start at 1 in this file, keeping whichever of
+// them each vertex had.
+fn fix_vertices(faces: &[&str], buf: String) -> Result<String, ClientError> {
     let mut lines = String::new();
-    let (min1, min2, min3) = get_min_vertices(faces);
+    let mins = get_min_vertices(faces)?;
 
-    for face in faces.iter_mut() {
-        let aux = face.split_whitespace().collect::<Vec<&str>>();
-        let aux = aux
-            .iter()
-            .map(|x| {
-                x.split("/")
-                    .map(|x| x.parse::<u32>().unwrap())
-                    .collect::<Vec<u32>>()
-            })
-            .collect::<Vec<Vec<u32>>>();
-        let mut f1 = aux.iter().map(|x| x[0]).collect::<Vec<u32>>();
-        let mut f2 = aux.iter().map(|x| x[1]).collect::<Vec<u32>>();
-        let mut f3 = aux.iter().map(|x| x[2]).collect::<Vec<u32>>();
-        f1 = f1.iter().map(|x| x - min1 + 1).collect();
-        f2 = f2.iter().map(|x| x - min2 + 1).collect();
-        f3 = f3.iter().map(|x| x - min3 + 1).collect();
-        for i in 0..f1.len() {
-            if i % 4 == 0 || i == 0 {
+    for face in faces.iter() {
+        for (i, vertex) in parse_face(face)?.into_iter().enumerate() {
+            if i % 4 == 0 {
                 lines.push_str("\nf ");
             }
-            lines.push_str(&format!("{}/{}/{} ", f1[i], f2[i], f3[i]));
+            let indices = vertex
+                .iter()
+                .zip(mins)
+                .map(|(index, min)| index.map(|x| (x - min + 1).to_string()).unwrap_or_default())
+                .collect::<Vec<String>>();
+            lines.push_str(indices.join("/").trim_end_matches('/'));
+            lines.push(' ');
         }
     }
     let new_data = buf
         .lines()
-        .filter(|x| &x[0..1] != "f")
+        .filter(|x| !x.starts_with('f'))
         .collect::<Vec<&str>>();
     let mut new_data = new_data.join("\n");
     new_data.push_str(&lines);
-    new_data
+    Ok(new_data)
 }
 
-pub fn delete_models() {
-    let files = std::fs::read_dir("static/models").unwrap();
+pub fn delete_models() -> Result<(), ClientError> {
+    let files = std::fs::read_dir("static/models")?;
     for entry in files {
-        let entry = entry.unwrap();
-        let name = entry.file_name();
-        let name = name.to_str().unwrap();
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
         if !name.contains("scene") {
-            std::fs::remove_file(format!("static/models/{}", name)).unwrap();
+            std::fs::remove_file(format!("static/models/{}", name))?;
         }
     }
+    Ok(())
 }
@@ -1,69 +1,446 @@
+use deku::ctx::Endian;
 use deku::prelude::*;
 
+use crate::auth::{ChallengeRequest, ProofRequest, VerifiedSessionRequest};
+use crate::codec::{wire_len, MAX_WIRE_BYTES, MAX_WIRE_ITEMS, WIRE_ENDIAN};
 use crate::network::Reason;
 
-#[derive(DekuRead, DekuWrite)]
+pub const MIN_PLAYER_LIMIT: u8 = 2;
+pub const MAX_PLAYER_LIMIT: u8 = 32;
+pub const MAX_MAP_NAME: usize = 32;
+pub const MAX_ROUND_TIME: u16 = 60 * 60;
+pub const MAX_NICKNAME: usize = 16;
+pub const MAX_PASSWORD: usize = 64;
+
+pub fn validate_nickname(nickname: &str) -> Result<(), Reason> {
+    let length = nickname.chars().count();
+    if length == 0 || length > MAX_NICKNAME || nickname.chars().any(char::is_control) {
+        return Err(Reason::InvalidNickname);
+    }
+    Ok(())
+}
+
+// Checked locally since with challenge-response the server never sees the
+// password to check it itself.
+pub fn validate_password(password: &str) -> Result<(), Reason> {
+    if password.len() > MAX_PASSWORD || password.chars().any(char::is_control) {
+        return Err(Reason::InvalidPassword);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
+pub enum GameMode {
+    #[deku(id = "0x1")]
+    FreeForAll,
+    #[deku(id = "0x2")]
+    Teams,
+    #[deku(id = "0x3")]
+    Sandbox,
+}
+
+impl GameMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GameMode::FreeForAll => "Free for all",
+            GameMode::Teams => "Teams",
+            GameMode::Sandbox => "Sandbox",
+        }
+    }
+
+    pub fn next(&self) -> Self {
+        match self {
+            GameMode::FreeForAll => GameMode::Teams,
+            GameMode::Teams => GameMode::Sandbox,
+            GameMode::Sandbox => GameMode::FreeForAll,
+        }
+    }
+}
+
+// Private sessions are left out of the session list and can only be joined
+// by id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
+pub enum Visibility {
+    #[deku(id = "0x1")]
+    Public,
+    #[deku(id = "0x2")]
+    Private,
+}
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct SessionOptions {
+    pub player_limit: u8,
+    #[deku(update = "self.map.len()", assert = "*map_count <= MAX_WIRE_BYTES")]
+    map_count: u16,
+    #[deku(count = "map_count")]
+    pub map: Vec<u8>,
+    pub mode: GameMode,
+    pub visibility: Visibility,
+    // In seconds, 0 means rounds never end on their own.
+    pub round_time_limit: u16,
+}
+
+impl SessionOptions {
+    pub fn new(
+        player_limit: u8,
+        map: &str,
+        mode: GameMode,
+        visibility: Visibility,
+        round_time_limit: u16,
+    ) -> Self {
+        Self {
+            player_limit,
+            map_count: wire_len(map.len()),
+            map: map.as_bytes().to_vec(),
+            mode,
+            visibility,
+            round_time_limit,
+        }
+    }
+
+    // Same bounds the server enforces, checked up front so an obviously bad
+    // form never makes the round trip.
+    pub fn validate(&self) -> Result<(), Reason> {
+        if !(MIN_PLAYER_LIMIT..=MAX_PLAYER_LIMIT).contains(&self.player_limit) {
+            return Err(Reason::InvalidPlayerLimit);
+        }
+        let valid_map = self
+            .map
+            .iter()
+            .all(|x| x.is_ascii_alphanumeric() || *x == b'_' || *x == b'-');
+        if self.map.is_empty() || self.map.len() > MAX_MAP_NAME || !valid_map {
+            return Err(Reason::InvalidMap);
+        }
+        if self.round_time_limit > MAX_ROUND_TIME {
+            return Err(Reason::InvalidRoundTime);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
 pub struct NewSessionRequest {
-    #[deku(update = "self.id.len()")]
-    id_count: usize,
+    #[deku(update = "self.id.len()", assert = "*id_count <= MAX_WIRE_BYTES")]
+    id_count: u16,
     #[deku(count = "id_count")]
     pub id: Vec<u8>,
-    #[deku(update = "self.password.len()")]
-    count: usize,
+    #[deku(update = "self.password.len()", assert = "*count <= MAX_WIRE_BYTES")]
+    count: u16,
     #[deku(count = "count")]
     pub password: Vec<u8>,
-    pub player_limit: u8,
+    #[deku(update = "self.nickname.len()", assert = "*nickname_count <= MAX_WIRE_BYTES")]
+    nickname_count: u16,
+    #[deku(count = "nickname_count")]
+    pub nickname: Vec<u8>,
+    pub options: SessionOptions,
 }
 
 impl NewSessionRequest {
-    pub fn new(id: &str, password: &str) -> Self {
+    pub fn new(id: &str, password: &str, nickname: &str, options: SessionOptions) -> Self {
         Self {
-            id_count: id.len(),
+            id_count: wire_len(id.len()),
             id: id.as_bytes().to_vec(),
-            count: password.len(),
+            count: wire_len(password.len()),
             password: password.as_bytes().to_vec(),
-            player_limit: 8
+            nickname_count: wire_len(nickname.len()),
+            nickname: nickname.as_bytes().to_vec(),
+            options,
         }
     }
 }
 
-#[derive(DekuRead, DekuWrite)]
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
 pub struct JoinSessionRequest {
-    #[deku(update = "self.id.len()")]
-    id_count: usize,
+    #[deku(update = "self.id.len()", assert = "*id_count <= MAX_WIRE_BYTES")]
+    id_count: u16,
     #[deku(count = "id_count")]
     pub id: Vec<u8>,
-    #[deku(update = "self.password.len()")]
-    count: usize,
+    #[deku(update = "self.password.len()", assert = "*count <= MAX_WIRE_BYTES")]
+    count: u16,
     #[deku(count = "count")]
     pub password: Vec<u8>,
+    #[deku(update = "self.nickname.len()", assert = "*nickname_count <= MAX_WIRE_BYTES")]
+    nickname_count: u16,
+    #[deku(count = "nickname_count")]
+    pub nickname: Vec<u8>,
 }
 
 impl JoinSessionRequest {
-    pub fn new(id: &str, password: &str) -> Self {
+    pub fn new(id: &str, password: &str, nickname: &str) -> Self {
         Self {
-            id_count: id.len(),
+            id_count: wire_len(id.len()),
             id: id.as_bytes().to_vec(),
-            count: password.len(),
-            password: password.as_bytes().to_vec()
+            count: wire_len(password.len()),
+            password: password.as_bytes().to_vec(),
+            nickname_count: wire_len(nickname.len()),
+            nickname: nickname.as_bytes().to_vec(),
         }
     }
 }
 
-#[derive(DekuRead, DekuWrite)]
-#[deku(type = "u8")]
+// Handed out on every successful create, join or resume; presenting the token
+// on a new connection puts the client back into the same session.
+#[derive(Clone, Copy, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct SessionTicket {
+    pub resume_token: u64,
+    pub player_id: u32,
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct ResumeRequest {
+    pub resume_token: u64,
+}
+
+impl ResumeRequest {
+    pub fn new(resume_token: u64) -> Self {
+        Self { resume_token }
+    }
+}
+
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
 pub enum ServerRequest {
     #[deku(id = "0x1")]
     NewSession(NewSessionRequest),
     #[deku(id = "0x2")]
     JoinSession(JoinSessionRequest),
+    #[deku(id = "0x3")]
+    BindUdp,
+    #[deku(id = "0x4")]
+    ResumeSession(ResumeRequest),
+    #[deku(id = "0x5")]
+    LeaveSession,
+    #[deku(id = "0x6")]
+    ListSessions,
+    #[deku(id = "0x7")]
+    NewVerifiedSession(VerifiedSessionRequest),
+    #[deku(id = "0x8")]
+    Challenge(ChallengeRequest),
+    #[deku(id = "0x9")]
+    Proof(ProofRequest),
 }
 
-#[derive(DekuRead, DekuWrite)]
-#[deku(type = "u8")]
+#[derive(Debug, DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
 pub enum JoinResponse {
+    #[deku(id = "0x1")]
+    Ok(SessionTicket),
+    #[deku(id = "0x2")]
+    Err(Reason),
+}
+
+#[derive(DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct UdpBinding {
+    pub token: u64,
+    pub port: u16,
+}
+
+#[derive(DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
+pub enum UdpBindResponse {
+    #[deku(id = "0x1")]
+    Ok(UdpBinding),
+    #[deku(id = "0x2")]
+    Err(Reason),
+}
+
+#[derive(DekuRead, DekuWrite)]
+#[deku(
+    type = "u8",
+    endian = "endian",
+    ctx = "endian: Endian",
+    ctx_default = "WIRE_ENDIAN"
+)]
+pub enum LeaveResponse {
     #[deku(id = "0x1")]
     Ok,
     #[deku(id = "0x2")]
     Err(Reason),
 }
+
+#[derive(Clone, Debug, DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct SessionInfo {
+    #[deku(update = "self.id.len()", assert = "*id_count <= MAX_WIRE_BYTES")]
+    id_count: u16,
+    #[deku(count = "id_count")]
+    pub id: Vec<u8>,
+    pub player_count: u8,
+    pub player_limit: u8,
+    pub has_password: bool,
+}
+
+impl SessionInfo {
+    pub fn new(id: &[u8], player_count: u8, player_limit: u8, has_password: bool) -> Self {
+        Self {
+            id_count: wire_len(id.len()),
+            id: id.to_vec(),
+            player_count,
+            player_limit,
+            has_password,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.id).into_owned()
+    }
+
+    // Whether the id can be shown and typed back in to join.
+    pub fn has_printable_id(&self) -> bool {
+        std::str::from_utf8(&self.id).is_ok_and(|id| !id.chars().any(char::is_control))
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.player_count >= self.player_limit
+    }
+}
+
+#[derive(DekuRead, DekuWrite)]
+#[deku(endian = "endian", ctx = "endian: Endian", ctx_default = "WIRE_ENDIAN")]
+pub struct SessionList {
+    #[deku(update = "self.sessions.len()", assert = "*count <= MAX_WIRE_ITEMS")]
+    count: u16,
+    #[deku(count = "count")]
+    pub sessions: Vec<SessionInfo>,
+}
+
+impl SessionList {
+    pub fn new(sessions: Vec<SessionInfo>) -> Self {
+        Self {
+            count: wire_len(sessions.len()),
+            sessions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use proptest::sample::select;
+    use proptest::strategy::LazyJust;
+
+    use super::*;
+    use crate::auth::DIGEST_SIZE;
+    use crate::codec::testing::{frame_round_trip, reason, round_trip, text};
+
+    prop_compose! {
+        fn options()(
+            player_limit in any::<u8>(),
+            map in text(),
+            mode in select(vec![GameMode::FreeForAll, GameMode::Teams, GameMode::Sandbox]),
+            visibility in select(vec![Visibility::Public, Visibility::Private]),
+            round_time_limit in any::<u16>(),
+        ) -> SessionOptions {
+            SessionOptions::new(player_limit, &map, mode, visibility, round_time_limit)
+        }
+    }
+
+    prop_compose! {
+        fn new_session()(
+            id in text(),
+            password in text(),
+            nickname in text(),
+            options in options(),
+        ) -> NewSessionRequest {
+            NewSessionRequest::new(&id, &password, &nickname, options)
+        }
+    }
+
+    prop_compose! {
+        fn join_session()(
+            id in text(),
+            password in text(),
+            nickname in text(),
+        ) -> JoinSessionRequest {
+            JoinSessionRequest::new(&id, &password, &nickname)
+        }
+    }
+
+    prop_compose! {
+        fn ticket()(resume_token in any::<u64>(), player_id in any::<u32>()) -> SessionTicket {
+            SessionTicket {
+                resume_token,
+                player_id,
+            }
+        }
+    }
+
+    // `NewVerifiedSession` has a test in auth.rs, building one draws a random salt.
+    fn server_request() -> impl Strategy<Value = ServerRequest> {
+        prop_oneof![
+            new_session().prop_map(ServerRequest::NewSession),
+            join_session().prop_map(ServerRequest::JoinSession),
+            LazyJust::new(|| ServerRequest::BindUdp),
+            any::<u64>().prop_map(|token| ServerRequest::ResumeSession(ResumeRequest::new(token))),
+            LazyJust::new(|| ServerRequest::LeaveSession),
+            LazyJust::new(|| ServerRequest::ListSessions),
+            (text(), text()).prop_map(|(id, nickname)| {
+                ServerRequest::Challenge(ChallengeRequest::new(&id, &nickname))
+            }),
+            any::<[u8; DIGEST_SIZE]>()
+                .prop_map(|proof| ServerRequest::Proof(ProofRequest { proof })),
+        ]
+    }
+
+    fn join_response() -> impl Strategy<Value = JoinResponse> {
+        prop_oneof![
+            ticket().prop_map(JoinResponse::Ok),
+            reason().prop_map(JoinResponse::Err),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn new_session_request_round_trips(request in new_session()) {
+            round_trip(&request)?;
+        }
+
+        #[test]
+        fn join_session_request_round_trips(request in join_session()) {
+            round_trip(&request)?;
+        }
+
+        #[test]
+        fn server_request_round_trips(request in server_request()) {
+            frame_round_trip(&request)?;
+        }
+
+        #[test]
+        fn join_response_round_trips(response in join_response()) {
+            frame_round_trip(&response)?;
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use aimclient::codec::{read_reply, write_frame};
+use aimclient::config::{Config, Transport};
+use aimclient::connection::Stream;
+use aimclient::mock_server::{Failure, MockServer, Script};
+use aimclient::netsim::NetConditions;
+use aimclient::network::{
+    get_stream, handshake, NetworkEvent, NetworkTask, PlayerSignal, ServerResponse, Session,
+};
+use aimclient::session::{
+    GameMode, JoinResponse, NewSessionRequest, ResumeRequest, ServerRequest, SessionList,
+    SessionOptions, Visibility,
+};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn start(failures: Vec<Failure>) -> SocketAddr {
+    let script = Script {
+        failures,
+        tick: Duration::from_millis(10),
+        ..Script::default()
+    };
+    let server = MockServer::bind(("127.0.0.1", 0), script).await.unwrap();
+    let addr = server.local_addr().unwrap();
+    server.spawn();
+    addr
+}
+
+fn config(addr: SocketAddr, transport: Transport) -> Config {
+    Config {
+        host: addr.ip().to_string(),
+        port: addr.port(),
+        transport,
+        heartbeat_interval: Duration::from_millis(20),
+        ..Config::default()
+    }
+}
+
+async fn connect(config: &Config) -> (Stream, u32) {
+    let mut stream = get_stream(config).await.unwrap();
+    let capabilities = handshake(&mut stream).await.unwrap();
+    (stream, capabilities)
+}
+
+async fn create(config: Config) -> (Stream, Session) {
+    let (mut stream, capabilities) = connect(&config).await;
+    let options = SessionOptions::new(8, "arena", GameMode::FreeForAll, Visibility::Public, 0);
+    let request = NewSessionRequest::new("room", "", "ana", options);
+    write_frame(&mut stream, &ServerRequest::NewSession(request)).await.unwrap();
+    let ticket = match read_reply(&mut stream).await.unwrap() {
+        ServerResponse::Ok(ticket, _) => ticket,
+        ServerResponse::InvalidRequest(reason) => panic!("session refused: {reason:?}"),
+    };
+    let session = Session {
+        config,
+        capabilities,
+        ticket,
+    };
+    (stream, session)
+}
+
+// Creates a session and hands the connection over to a `NetworkTask`, the
+// same way the game does once the lobby is done.
+async fn play(addr: SocketAddr, transport: Transport) -> (NetworkTask, bool) {
+    let (mut stream, session) = create(config(addr, transport)).await;
+    let datagrams = session.open_datagrams(&mut stream).await.unwrap();
+    let udp = datagrams.is_some();
+    let conditions = Arc::new(Mutex::new(NetConditions::default()));
+    (NetworkTask::spawn(stream, datagrams, session, conditions), udp)
+}
+
+fn signal(sequence: u32) -> PlayerSignal {
+    PlayerSignal {
+        sequence,
+        client_tick: sequence,
+        desired_mov: [0.0; 3],
+        desired_rot: [0.0; 2],
+        camera_radius: 5.0,
+        snapshot_ack: 0,
+    }
+}
+
+// Polls like the render loop does until `done` picks an event, failing the
+// test on a disconnect or once `WAIT` is up.
+async fn wait_for<T>(
+    network: &mut NetworkTask,
+    mut done: impl FnMut(&NetworkEvent) -> Option<T>,
+) -> T {
+    let deadline = Instant::now() + WAIT;
+    while Instant::now() < deadline {
+        for event in network.poll() {
+            if let NetworkEvent::Disconnected(err) = &event {
+                panic!("disconnected: {err}");
+            }
+            if let Some(found) = done(&event) {
+                return found;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    panic!("timed out waiting for an event");
+}
+
+// Keeps sending inputs until a snapshot acknowledges one of them.
+async fn acked_input(network: &mut NetworkTask) -> u32 {
+    for sequence in 1.. {
+        network.send(signal(sequence));
+        let acked = wait_for(network, |event| match event {
+            NetworkEvent::Snapshot(snapshot, _) => Some(snapshot.last_input),
+            _ => None,
+        })
+        .await;
+        if acked > 0 {
+            return acked;
+        }
+    }
+    unreachable!()
+}
+
+#[tokio::test]
+async fn snapshots_acknowledge_inputs_over_tcp() {
+    let (mut network, udp) = play(start(Vec::new()).await, Transport::Tcp).await;
+    assert!(!udp);
+    let acked = acked_input(&mut network).await;
+    assert!(network.inputs().round_trips().any(|(sequence, _)| *sequence == acked));
+}
+
+#[tokio::test]
+async fn inputs_and_snapshots_travel_over_udp() {
+    let (mut network, udp) = play(start(Vec::new()).await, Transport::Udp).await;
+    assert!(udp);
+    // Inputs only reach the mock as datagrams, and once one did snapshots
+    // only leave it as datagrams too.
+    acked_input(&mut network).await;
+    let sequence = 1000;
+    network.send(signal(sequence));
+    wait_for(&mut network, |event| match event {
+        NetworkEvent::Snapshot(snapshot, _) if snapshot.last_input == sequence => Some(()),
+        _ => None,
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn pings_measure_the_round_trip() {
+    let (mut network, _) = play(start(Vec::new()).await, Transport::Tcp).await;
+    let deadline = Instant::now() + WAIT;
+    while network.stats().rtt.is_none() {
+        assert!(Instant::now() < deadline, "no pong came back");
+        network.poll();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+}
+
+#[tokio::test]
+async fn leaving_frees_the_slot() {
+    let addr = start(Vec::new()).await;
+    let (mut network, _) = play(addr, Transport::Udp).await;
+    acked_input(&mut network).await;
+    network.leave().await;
+
+    let config = config(addr, Transport::Tcp);
+    let (mut stream, _) = connect(&config).await;
+    write_frame(&mut stream, &ServerRequest::ListSessions).await.unwrap();
+    let list: SessionList = read_reply(&mut stream).await.unwrap();
+    assert!(list.sessions.is_empty());
+}
+
+#[tokio::test]
+async fn dropped_connection_resumes_the_session() {
+    for transport in [Transport::Tcp, Transport::Udp] {
+        let addr = start(vec![Failure::DisconnectAfter(3)]).await;
+        let (mut network, _) = play(addr, transport).await;
+        wait_for(&mut network, |event| match event {
+            NetworkEvent::Reconnecting(_) => Some(()),
+            _ => None,
+        })
+        .await;
+        wait_for(&mut network, |event| match event {
+            NetworkEvent::Resumed => Some(()),
+            _ => None,
+        })
+        .await;
+        acked_input(&mut network).await;
+    }
+}
+
+#[tokio::test]
+async fn left_session_cannot_be_resumed() {
+    let addr = start(Vec::new()).await;
+    let config = config(addr, Transport::Tcp);
+    let (stream, session) = create(config.clone()).await;
+    let resume_token = session.ticket.resume_token;
+    let conditions = Arc::new(Mutex::new(NetConditions::default()));
+    NetworkTask::spawn(stream, None, session, conditions).leave().await;
+
+    let (mut stream, _) = connect(&config).await;
+    let request = ServerRequest::ResumeSession(ResumeRequest::new(resume_token));
+    write_frame(&mut stream, &request).await.unwrap();
+    let response: JoinResponse = read_reply(&mut stream).await.unwrap();
+    assert!(matches!(response, JoinResponse::Err(_)));
+}